@@ -0,0 +1,291 @@
+use std::f32::consts::PI;
+use std::path::Path;
+
+use anyhow::bail;
+use rustfft::num_complex::Complex;
+use rustfft::num_traits::Zero;
+use rustfft::FFT;
+
+use crate::envelope::{Adsr, Curve};
+use crate::patch::{Patch, MAX_PARTIALS};
+use crate::wav;
+
+/// Samples in the frame the fundamental and the harmonic amplitudes are measured in, fine
+/// enough to separate the harmonics of low notes.
+const WINDOW: usize = 8192;
+
+/// Frames for following the harmonics over time, shorter so attacks aren't smeared out.
+const TRACK_WINDOW: usize = 2048;
+const HOP: usize = 512;
+
+/// Range searched for the fundamental, A0 to C8.
+const MIN_FUNDAMENTAL: f32 = 27.5;
+const MAX_FUNDAMENTAL: f32 = 4186.0;
+
+/// Harmonics multiplied together by the harmonic product spectrum.
+const HPS_HARMONICS: usize = 4;
+
+/// Harmonics quieter than this relative to the loudest one are left out.
+const FLOOR: f32 = 0.001;
+
+pub struct Analysis {
+    /// Detected fundamental in Hz.
+    pub fundamental: f32,
+    pub patch: Patch,
+}
+
+/// Magnitude spectrum of the first `len` samples of `frame` after a Hann window, zero padded
+/// if it is shorter.
+fn spectrum(fft: &dyn FFT<f32>, frame: &[f32], len: usize) -> Vec<f32> {
+    let mut input = (0..len)
+        .map(|i| {
+            let hann = 0.5 - 0.5 * (2.0 * PI * i as f32 / len as f32).cos();
+            Complex::new(frame.get(i).cloned().unwrap_or(0.0) * hann, 0.0)
+        })
+        .collect::<Vec<_>>();
+    let mut output = vec![Complex::zero(); len];
+    fft.process(&mut input, &mut output);
+
+    output[..len / 2].iter().map(|c| c.norm()).collect()
+}
+
+/// Peak of `magnitudes` within `from..to`, refined by fitting a parabola through it and its
+/// neighbours. Returns the fractional bin and the magnitude there.
+fn peak(magnitudes: &[f32], from: usize, to: usize) -> Option<(f32, f32)> {
+    let to = to.min(magnitudes.len());
+    let (bin, &magnitude) = magnitudes[from..to]
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+    let bin = from + bin;
+
+    if bin == 0 || bin + 1 >= magnitudes.len() {
+        return Some((bin as f32, magnitude));
+    }
+    let (left, right) = (magnitudes[bin - 1], magnitudes[bin + 1]);
+    let curvature = left - 2.0 * magnitude + right;
+    if curvature >= 0.0 {
+        return Some((bin as f32, magnitude));
+    }
+    let offset = 0.5 * (left - right) / curvature;
+
+    Some((
+        bin as f32 + offset,
+        magnitude - 0.25 * (left - right) * offset,
+    ))
+}
+
+/// Finds the fundamental bin with the harmonic product spectrum: the spectrum is multiplied
+/// by copies of itself squeezed by 2, 3 and 4, which lines the harmonics up on the
+/// fundamental even when it is weaker than its overtones.
+fn fundamental_bin(magnitudes: &[f32], bin_width: f32) -> Option<f32> {
+    let from = (MIN_FUNDAMENTAL / bin_width).floor().max(1.0) as usize;
+    let to = ((MAX_FUNDAMENTAL / bin_width).ceil() as usize).min(magnitudes.len() / HPS_HARMONICS);
+
+    let product = (from..to)
+        .map(|bin| {
+            (1..=HPS_HARMONICS)
+                .map(|h| (magnitudes[bin * h] + 1e-9).ln())
+                .sum::<f32>()
+        })
+        .collect::<Vec<_>>();
+    let (best, _) = product
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))?;
+
+    // The product is coarse, take the exact position from the spectrum itself
+    let bin = from + best;
+    peak(magnitudes, bin.saturating_sub(1).max(1), bin + 2).map(|(bin, _)| bin)
+}
+
+/// Fits an envelope to levels measured every `step` seconds.
+fn fit_envelope(levels: &[f32], step: f32) -> Adsr {
+    let mut envelope = Adsr {
+        attack: 0.001,
+        decay: 0.0,
+        sustain: 1.0,
+        release: 0.05,
+        curve: Curve::Exponential,
+    };
+
+    let peak = levels.iter().cloned().fold(0.0, f32::max);
+    if peak <= 0.0 {
+        return envelope;
+    }
+    let level = |i: usize| levels[i] / peak;
+    // The attack ends once the level comes close to its peak, so a steady tone that wobbles a
+    // little doesn't get its peak somewhere in the middle
+    let peak_index = (0..levels.len()).find(|&i| level(i) >= 0.9).unwrap_or(0);
+    let end = (0..levels.len())
+        .rev()
+        .find(|&i| level(i) > FLOOR)
+        .map_or(peak_index + 1, |i| i + 1);
+
+    // The sustain level is taken from the middle of the note, everything after it counts as
+    // the release
+    let sustain_from = peak_index + (end - peak_index) / 2;
+    let sustain_to = (peak_index + (end - peak_index) * 4 / 5)
+        .max(sustain_from + 1)
+        .min(end);
+    envelope.sustain = if sustain_from < sustain_to {
+        (sustain_from..sustain_to).map(level).sum::<f32>() / (sustain_to - sustain_from) as f32
+    } else {
+        0.0
+    };
+
+    let settled = envelope.sustain + 0.1 * (1.0 - envelope.sustain);
+    let decay_end = (peak_index..end)
+        .find(|&i| level(i) <= settled)
+        .unwrap_or(end);
+
+    envelope.attack = (peak_index as f32 * step).max(0.001);
+    envelope.decay = (decay_end - peak_index) as f32 * step;
+    envelope.release = ((end - sustain_to) as f32 * step).max(0.05);
+    envelope
+}
+
+/// Measures a recording of a single note: its fundamental, the amplitude and tuning of each
+/// harmonic, and the overall envelope. With `envelopes`, every harmonic also gets an envelope
+/// of its own, following how it rises and dies away in the recording.
+pub fn analyze(
+    samples: &[f32],
+    sample_rate: u32,
+    envelopes: bool,
+) -> Result<Analysis, anyhow::Error> {
+    let step = HOP as f32 / sample_rate as f32;
+    let track_fft = rustfft::algorithm::Radix4::new(TRACK_WINDOW, false);
+    // Only frames that lie within the recording, zero padding would make them look quieter
+    let frame_count = samples.len().saturating_sub(TRACK_WINDOW) / HOP + 1;
+    let frames = (0..frame_count)
+        .map(|i| {
+            spectrum(
+                &track_fft,
+                &samples[(i * HOP).min(samples.len())..],
+                TRACK_WINDOW,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let loudness = frames
+        .iter()
+        .map(|frame| frame.iter().map(|m| m * m).sum::<f32>().sqrt())
+        .collect::<Vec<_>>();
+    let loudest = match loudness
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+    {
+        Some((i, &loudness)) if loudness > 0.0 => i,
+        _ => bail!("the recording is silent"),
+    };
+
+    // Measure where the note is loudest, past the attack
+    let fft = rustfft::algorithm::Radix4::new(WINDOW, false);
+    let start = (loudest * HOP).min(samples.len().saturating_sub(WINDOW));
+    let magnitudes = spectrum(&fft, &samples[start..], WINDOW);
+    let bin_width = sample_rate as f32 / WINDOW as f32;
+
+    let fundamental = match fundamental_bin(&magnitudes, bin_width) {
+        Some(bin) => bin * bin_width,
+        None => bail!("no fundamental found"),
+    };
+
+    let harmonics = ((0.45 * sample_rate as f32 / fundamental) as usize).clamp(1, MAX_PARTIALS);
+    let mut measured = (1..=harmonics)
+        .map(|n| {
+            // Search almost a third of a harmonic either way, so stretched partials are still
+            // found without picking up their neighbours
+            let center = n as f32 * fundamental / bin_width;
+            let reach = 0.3 * fundamental / bin_width;
+            let from = (center - reach).max(1.0) as usize;
+            let to = (center + reach).ceil() as usize + 1;
+            peak(&magnitudes, from, to).unwrap_or((center, 0.0))
+        })
+        .collect::<Vec<_>>();
+
+    let loudest_harmonic = measured.iter().map(|m| m.1).fold(0.0, f32::max);
+    for harmonic in &mut measured {
+        if harmonic.1 < FLOOR * loudest_harmonic {
+            harmonic.1 = 0.0;
+        }
+    }
+    // Drop the silent harmonics at the top
+    while measured.len() > 1 && measured.last().is_some_and(|m| m.1 == 0.0) {
+        measured.pop();
+    }
+
+    let mut patch = Patch::new();
+    patch.partials.truncate(measured.len());
+    patch.envelope = fit_envelope(&loudness, step);
+
+    let mut amplitudes = measured.iter().map(|m| m.1).collect::<Vec<_>>();
+    for (i, (partial, &(bin, magnitude))) in patch.partials.iter_mut().zip(&measured).enumerate() {
+        let n = (i + 1) as f32;
+        partial.ratio = n;
+        partial.detune = if magnitude > 0.0 {
+            1200.0 * (bin * bin_width / (n * fundamental)).log2()
+        } else {
+            0.0
+        };
+
+        if envelopes && magnitude > 0.0 {
+            let track_bin =
+                (bin * bin_width * TRACK_WINDOW as f32 / sample_rate as f32).round() as usize;
+            let levels = frames
+                .iter()
+                .map(|frame| {
+                    peak(frame, track_bin.saturating_sub(1).max(1), track_bin + 2)
+                        .map_or(0.0, |(_, magnitude)| magnitude)
+                })
+                .collect::<Vec<_>>();
+            partial.envelope = Some(fit_envelope(&levels, step));
+
+            // The envelope peaks at 1.0, so the amplitude is the peak of the partial
+            amplitudes[i] = levels.iter().cloned().fold(0.0, f32::max);
+        }
+    }
+    patch.set_amplitudes(&amplitudes);
+
+    Ok(Analysis { fundamental, patch })
+}
+
+/// Analyzes a WAV file, see `analyze`.
+pub fn analyze_file(path: &Path, envelopes: bool) -> Result<Analysis, anyhow::Error> {
+    let (sample_rate, samples) = wav::read_mono(path)?;
+    analyze(&samples, sample_rate, envelopes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_a_synthetic_note() {
+        let sample_rate = 48_000;
+        let amplitudes = [1.0, 0.5, 0.0, 0.25];
+        let samples = (0..sample_rate)
+            .map(|i| {
+                let t = i as f32 / sample_rate as f32;
+                amplitudes
+                    .iter()
+                    .enumerate()
+                    .map(|(h, a)| a * (2.0 * PI * 220.0 * (h + 1) as f32 * t).sin())
+                    .sum::<f32>()
+                    * 0.3
+            })
+            .collect::<Vec<_>>();
+
+        let analysis = analyze(&samples, sample_rate as u32, true).unwrap();
+        assert!((analysis.fundamental - 220.0).abs() < 0.5);
+
+        // Stored relative to a saw, so harmonic n is scaled by n
+        let partials = &analysis.patch.partials;
+        assert_eq!(partials.len(), 4);
+        for (partial, expected) in partials.iter().zip(&[1.0, 1.0, 0.0, 1.0]) {
+            assert!((partial.amplitude - expected).abs() < 0.05);
+            assert!(partial.detune.abs() < 5.0);
+        }
+        assert!(partials[0].envelope.as_ref().unwrap().sustain > 0.8);
+    }
+}
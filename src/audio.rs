@@ -1,91 +1,465 @@
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 
-use std::sync::{Arc, Mutex};
-
-use crate::synth::Synth;
-
-#[cfg_attr(target_os = "android", ndk_glue::main(backtrace = "full"))]
-pub fn setup_audio(synth: &Arc<Mutex<Synth>>) {
-    // Conditionally compile with jack if the feature is specified.
-    #[cfg(all(
-        any(target_os = "linux", target_os = "dragonfly", target_os = "freebsd"),
-        feature = "jack"
-    ))]
-    // Manually check for flags. Can be passed through cargo with -- e.g.
-    // cargo run --release --example beep --features jack -- --jack
-    let host = if std::env::args()
-        .collect::<String>()
-        .contains(&String::from("--jack"))
-    {
-        cpal::host_from_id(cpal::available_hosts()
-            .into_iter()
-            .find(|id| *id == cpal::HostId::Jack)
-            .expect(
-                "make sure --features jack is specified. only works on OSes where jack is available",
-            )).expect("jack host unavailable")
-    } else {
-        cpal::default_host()
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use anyhow::{anyhow, bail};
+
+use crate::remote::Processor;
+use crate::ringbuffer::{ring_buffer, Consumer, Producer};
+
+/// Samples rendered at a time before converting them for the device, larger callbacks are
+/// rendered in several goes.
+const RENDER_BUFFER: usize = 8192;
+
+/// Sample rates offered for selection, when the device supports them.
+const COMMON_SAMPLE_RATES: [u32; 8] = [
+    22_050, 32_000, 44_100, 48_000, 88_200, 96_000, 176_400, 192_000,
+];
+
+/// Which output to open. Anything left at `None` uses what the host considers default.
+#[derive(Clone, Default, PartialEq)]
+pub struct AudioSettings {
+    pub host: Option<String>,
+    pub device: Option<String>,
+    pub sample_rate: Option<u32>,
+    /// In frames.
+    pub buffer_size: Option<u32>,
+}
+
+impl AudioSettings {
+    fn path() -> Option<PathBuf> {
+        Some(crate::config_dir()?.join("audio.cfg"))
+    }
+
+    /// Settings saved by the last run, defaults if there are none.
+    pub fn load() -> Self {
+        let mut settings = AudioSettings::default();
+
+        let text = match Self::path().and_then(|path| fs::read_to_string(path).ok()) {
+            Some(text) => text,
+            None => return settings,
+        };
+
+        for line in text.lines().map(str::trim) {
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                let value = value.trim().to_string();
+                match key.trim() {
+                    "host" => settings.host = Some(value),
+                    "device" => settings.device = Some(value),
+                    "sample_rate" => settings.sample_rate = value.parse().ok(),
+                    "buffer_size" => settings.buffer_size = value.parse().ok(),
+                    _ => {}
+                }
+            }
+        }
+
+        settings
+    }
+
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        let path = Self::path().ok_or_else(|| anyhow!("no config directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut text = String::from("# music-box audio output, delete a line to use the default\n");
+        if let Some(host) = &self.host {
+            text += &format!("host = {}\n", host);
+        }
+        if let Some(device) = &self.device {
+            text += &format!("device = {}\n", device);
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            text += &format!("sample_rate = {}\n", sample_rate);
+        }
+        if let Some(buffer_size) = self.buffer_size {
+            text += &format!("buffer_size = {}\n", buffer_size);
+        }
+
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// Overrides from the command line: --host, --device, --sample-rate and --buffer-size.
+    /// --jack is short for --host jack.
+    pub fn apply_args(&mut self, args: &[String]) -> Result<(), anyhow::Error> {
+        let value = |flag: &str| -> Result<Option<&String>, anyhow::Error> {
+            match args.iter().position(|a| a == flag) {
+                Some(idx) => match args.get(idx + 1) {
+                    Some(value) => Ok(Some(value)),
+                    None => bail!("{} expects a value", flag),
+                },
+                None => Ok(None),
+            }
+        };
+
+        if args.iter().any(|a| a == "--jack") {
+            self.host = Some(String::from("JACK"));
+        }
+        if let Some(host) = value("--host")? {
+            self.host = Some(host.clone());
+        }
+        if let Some(device) = value("--device")? {
+            self.device = Some(device.clone());
+        }
+        if let Some(sample_rate) = value("--sample-rate")? {
+            self.sample_rate = Some(sample_rate.parse()?);
+        }
+        if let Some(buffer_size) = value("--buffer-size")? {
+            self.buffer_size = Some(buffer_size.parse()?);
+        }
+
+        Ok(())
+    }
+}
+
+/// Names of the audio hosts compiled in and available on this system.
+pub fn host_names() -> Vec<&'static str> {
+    cpal::available_hosts().iter().map(|id| id.name()).collect()
+}
+
+/// Looks up a host by name, ignoring case, or the default host for `None`.
+pub fn open_host(name: Option<&str>) -> Result<cpal::Host, anyhow::Error> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(cpal::default_host()),
     };
 
-    #[cfg(any(
-        not(any(target_os = "linux", target_os = "dragonfly", target_os = "freebsd")),
-        not(feature = "jack")
-    ))]
-    let host = cpal::default_host();
+    let id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            anyhow!(
+                "unknown audio host {}, available are {}",
+                name,
+                host_names().join(", ")
+            )
+        })?;
 
-    let device = host
-        .default_output_device()
-        .expect("failed to find a default output device");
-    let config = device.default_output_config().unwrap();
+    Ok(cpal::host_from_id(id)?)
+}
 
-    match config.sample_format() {
-        cpal::SampleFormat::F32 => setup_synth::<f32>(&device, &config.into(), synth).unwrap(),
-        cpal::SampleFormat::U16 => setup_synth::<u16>(&device, &config.into(), synth).unwrap(),
-        cpal::SampleFormat::I16 => setup_synth::<i16>(&device, &config.into(), synth).unwrap(),
+/// An output device and what it can be opened with.
+pub struct DeviceInfo {
+    pub name: String,
+    pub sample_rates: Vec<u32>,
+    /// Smallest and largest buffer in frames, if the host can tell.
+    pub buffer_sizes: Option<(u32, u32)>,
+}
+
+pub fn output_devices(host: &cpal::Host) -> Result<Vec<DeviceInfo>, anyhow::Error> {
+    let mut devices = vec![];
+
+    for device in host.output_devices()? {
+        let name = match device.name() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+
+        // Devices that are busy or gone fail here, list them without options
+        let ranges = device
+            .supported_output_configs()
+            .map(|configs| configs.collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        let sample_rates = COMMON_SAMPLE_RATES
+            .iter()
+            .cloned()
+            .filter(|&rate| {
+                ranges
+                    .iter()
+                    .any(|r| r.min_sample_rate().0 <= rate && rate <= r.max_sample_rate().0)
+            })
+            .collect();
+
+        let buffer_sizes = ranges
+            .iter()
+            .filter_map(|r| match r.buffer_size() {
+                cpal::SupportedBufferSize::Range { min, max } => Some((*min, *max)),
+                cpal::SupportedBufferSize::Unknown => None,
+            })
+            .fold(None, |acc: Option<(u32, u32)>, (min, max)| match acc {
+                Some((lo, hi)) => Some((lo.min(min), hi.max(max))),
+                None => Some((min, max)),
+            });
+
+        devices.push(DeviceInfo {
+            name,
+            sample_rates,
+            buffer_sizes,
+        });
     }
+
+    Ok(devices)
 }
 
-fn setup_synth<T>(
-    device: &cpal::Device,
-    config: &cpal::StreamConfig,
-    synth: &Arc<Mutex<Synth>>,
-) -> Result<(), anyhow::Error>
-where
-    T: cpal::Sample,
-{
-    let sample_rate = config.sample_rate.0 as f32;
-    let channels = config.channels as usize;
+/// Lives inside the stream callback. Sends the processor back to the engine when the stream
+/// drops the callback, so the next stream can pick it up.
+struct Slot {
+    processor: Option<Processor>,
+    home: Producer<Processor>,
+    /// Rendered samples before they are converted to the device format.
+    buffer: Vec<f32>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(processor) = self.processor.take() {
+            let _ = self.home.push(processor);
+        }
+    }
+}
+
+struct Running {
+    stream: cpal::Stream,
+    device: String,
+    sample_rate: u32,
+}
+
+/// Owns the output stream and the `Processor` feeding it.
+pub struct AudioEngine {
+    settings: AudioSettings,
+    host: cpal::Host,
+    /// Output devices of `host`, as of the last `refresh`.
+    devices: Vec<DeviceInfo>,
+    default_device: Option<String>,
+    running: Option<Running>,
+    /// The processor while no stream owns it.
+    processor: Option<Processor>,
+    /// Where the last stream hands the processor back.
+    returning: Option<Consumer<Processor>>,
+    errors_tx: Sender<cpal::StreamError>,
+    errors: Receiver<cpal::StreamError>,
+    /// Last thing that went wrong, for showing in the UI.
+    pub error: Option<String>,
+}
+
+impl AudioEngine {
+    pub fn new(processor: Processor, settings: AudioSettings) -> Self {
+        let (errors_tx, errors) = mpsc::channel();
+
+        let (host, error) = match open_host(settings.host.as_deref()) {
+            Ok(host) => (host, None),
+            Err(e) => (cpal::default_host(), Some(e.to_string())),
+        };
+
+        let mut engine = AudioEngine {
+            settings,
+            host,
+            devices: vec![],
+            default_device: None,
+            running: None,
+            processor: Some(processor),
+            returning: None,
+            errors_tx,
+            errors,
+            error,
+        };
+        engine.refresh();
+        engine
+    }
+
+    pub fn settings(&self) -> &AudioSettings {
+        &self.settings
+    }
+
+    pub fn devices(&self) -> &[DeviceInfo] {
+        &self.devices
+    }
 
-    let err_fn = |err| eprintln!("an error occurred on stream: {}", err);
+    /// The device that is opened when the settings don't name one.
+    pub fn default_device(&self) -> Option<&str> {
+        self.default_device.as_deref()
+    }
+
+    /// Enumerates the devices of the current host again, e.g. after plugging one in.
+    pub fn refresh(&mut self) {
+        self.default_device = self
+            .host
+            .default_output_device()
+            .and_then(|d| d.name().ok());
+
+        match output_devices(&self.host) {
+            Ok(devices) => self.devices = devices,
+            Err(e) => {
+                self.devices.clear();
+                self.error = Some(format!("failed to list devices: {}", e));
+            }
+        }
+    }
+
+    /// Switches to other settings and reopens the output with them. If the host can't be
+    /// opened, the old settings stay and the output keeps playing.
+    pub fn configure(&mut self, settings: AudioSettings) -> Result<(), anyhow::Error> {
+        if settings.host != self.settings.host {
+            let host = open_host(settings.host.as_deref())?;
+            self.stop();
+            self.host = host;
+            self.settings = settings;
+            self.refresh();
+        } else {
+            self.settings = settings;
+        }
 
-    // let synth = Arc::new(Mutex::new(Synth::new(sample_rate)));
-    synth.lock().unwrap().sample_rate = sample_rate;
+        self.restart()
+    }
 
-    let x = synth.clone();
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
+    }
 
-    let stream = device.build_output_stream(
-        config,
-        move |data: &mut [T], _: &cpal::OutputCallbackInfo| write_data(data, channels, &x),
-        err_fn,
-    )?;
-    stream.play()?;
+    /// Name and sample rate of the open device.
+    pub fn device(&self) -> Option<(&str, u32)> {
+        self.running
+            .as_ref()
+            .map(|r| (r.device.as_str(), r.sample_rate))
+    }
 
-    // TODO: Fix this
-    std::mem::forget(stream); // Necessary, otherwise stream stop playing!
+    /// Opens the configured output device and starts playing. Does nothing if already running.
+    pub fn start(&mut self) -> Result<(), anyhow::Error> {
+        if self.running.is_some() {
+            return Ok(());
+        }
+
+        if self.processor.is_none() {
+            self.processor = self.returning.as_mut().and_then(|r| r.pop());
+        }
+        let processor = self
+            .processor
+            .take()
+            .ok_or_else(|| anyhow!("the previous stream is still shutting down"))?;
 
-    Ok(())
+        let (home, returning) = ring_buffer(1);
+        self.returning = Some(returning);
+        let slot = Slot {
+            processor: Some(processor),
+            home,
+            buffer: vec![0.0; RENDER_BUFFER],
+        };
+
+        let device = match &self.settings.device {
+            Some(name) => self
+                .host
+                .output_devices()?
+                .find(|d| d.name().map(|n| &n == name).unwrap_or(false))
+                .ok_or_else(|| anyhow!("output device {} not found", name))?,
+            None => self
+                .host
+                .default_output_device()
+                .ok_or_else(|| anyhow!("no output device available"))?,
+        };
+
+        let supported = match self.settings.sample_rate {
+            Some(rate) => device
+                .supported_output_configs()?
+                .filter(|r| r.min_sample_rate().0 <= rate && rate <= r.max_sample_rate().0)
+                .max_by(|a, b| a.cmp_default_heuristics(b))
+                .ok_or_else(|| anyhow!("{} Hz is not supported by the device", rate))?
+                .with_sample_rate(cpal::SampleRate(rate)),
+            None => device.default_output_config()?,
+        };
+        let mut config = supported.config();
+        if let Some(frames) = self.settings.buffer_size {
+            config.buffer_size = cpal::BufferSize::Fixed(frames);
+        }
+
+        let stream = match supported.sample_format() {
+            cpal::SampleFormat::F32 => self.build_stream::<f32>(&device, &config, slot),
+            cpal::SampleFormat::U16 => self.build_stream::<u16>(&device, &config, slot),
+            cpal::SampleFormat::I16 => self.build_stream::<i16>(&device, &config, slot),
+        }?;
+        stream.play()?;
+
+        self.running = Some(Running {
+            stream,
+            device: device
+                .name()
+                .unwrap_or_else(|_| String::from("unknown device")),
+            sample_rate: config.sample_rate.0,
+        });
+        self.error = None;
+
+        Ok(())
+    }
+
+    /// Closes the stream. The processor keeps its state and continues on the next `start`.
+    pub fn stop(&mut self) {
+        if let Some(running) = self.running.take() {
+            drop(running.stream);
+            self.processor = self.returning.as_mut().and_then(|r| r.pop());
+        }
+    }
+
+    /// Closes and reopens the device, e.g. after it was disconnected or the default output changed.
+    pub fn restart(&mut self) -> Result<(), anyhow::Error> {
+        self.stop();
+        self.start()
+    }
+
+    /// Picks up errors reported by the stream, call once per UI frame. A device that went away
+    /// stops the engine until it is restarted.
+    pub fn poll(&mut self) {
+        while let Ok(error) = self.errors.try_recv() {
+            if let cpal::StreamError::DeviceNotAvailable = error {
+                self.stop();
+            }
+            self.error = Some(error.to_string());
+        }
+    }
+
+    fn build_stream<T>(
+        &self,
+        device: &cpal::Device,
+        config: &cpal::StreamConfig,
+        mut slot: Slot,
+    ) -> Result<cpal::Stream, anyhow::Error>
+    where
+        T: cpal::Sample,
+    {
+        let channels = config.channels as usize;
+
+        if let Some(processor) = &mut slot.processor {
+            processor.synth.set_sample_rate(config.sample_rate.0 as f32);
+        }
+
+        let errors = self.errors_tx.clone();
+
+        // The callback owns the synth from here on, everything else talks to it through queues
+        let stream = device.build_output_stream(
+            config,
+            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                if let Some(processor) = &mut slot.processor {
+                    write_data(data, channels, processor, &mut slot.buffer)
+                }
+            },
+            move |err| {
+                let _ = errors.send(err);
+            },
+        )?;
+
+        Ok(stream)
+    }
 }
 
-fn write_data<T>(output: &mut [T], channels: usize, next_sample: &Arc<Mutex<Synth>>)
+fn write_data<T>(output: &mut [T], channels: usize, processor: &mut Processor, buffer: &mut [f32])
 where
     T: cpal::Sample,
 {
-    if let Ok(lock) = next_sample.lock().as_mut() {
-        for frame in output.chunks_mut(channels) {
-            let value: T = cpal::Sample::from::<f32>(&lock.next_sample());
-            for sample in frame.iter_mut() {
-                *sample = value;
-            }
+    processor.receive();
+
+    let chunk_frames = buffer.len() / channels;
+    for chunk in output.chunks_mut(chunk_frames * channels) {
+        let rendered = &mut buffer[..chunk.len()];
+        processor.render(rendered, channels);
+        for (sample, value) in chunk.iter_mut().zip(rendered.iter()) {
+            *sample = cpal::Sample::from::<f32>(value);
         }
     }
+
+    processor.report();
 }
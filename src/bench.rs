@@ -0,0 +1,98 @@
+use std::convert::TryFrom;
+use std::hint::black_box;
+use std::time::Instant;
+
+use wmidi::Note;
+
+use crate::oscillator::{self, Bank};
+use crate::synth::Synth;
+
+const SAMPLE_RATE: usize = 48_000;
+
+/// Audio rendered per measurement, in seconds.
+const SECONDS: usize = 5;
+
+const PARTIALS: usize = 64;
+
+/// Same as the synth's block size.
+const BLOCK: usize = 64;
+
+/// Voices held down for the whole synth measurement.
+const VOICES: u8 = 16;
+
+/// Seconds it takes `render` to produce `SECONDS` of a single voice, block by block. It gets
+/// the start phase and increment of every partial.
+fn time_voice(mut render: impl FnMut(&[f64], &[f64], &mut [f32], &mut [f32])) -> f64 {
+    let increments = (1..=PARTIALS)
+        .map(|n| 110.0 * n as f64 / SAMPLE_RATE as f64)
+        .collect::<Vec<_>>();
+    let mut phases = vec![0.0; PARTIALS];
+
+    let start = Instant::now();
+    for _ in 0..SECONDS * SAMPLE_RATE / BLOCK {
+        let mut left = [0.0; BLOCK];
+        let mut right = [0.0; BLOCK];
+        render(&phases, &increments, &mut left, &mut right);
+        black_box((&left, &right));
+
+        for (phase, increment) in phases.iter_mut().zip(&increments) {
+            *phase = (*phase + increment * BLOCK as f64).fract();
+        }
+    }
+    start.elapsed().as_secs_f64()
+}
+
+/// Prints how many voices of 64 partials fit in real time with a `sin()` call per sample and
+/// with the oscillator bank, then how the whole synth does. Only meaningful in release builds.
+pub fn run() {
+    let gains = [0.5, 0.5];
+
+    let direct = time_voice(|phases, increments, left, right| {
+        for (&phase, &increment) in phases.iter().zip(increments) {
+            oscillator::render_direct(phase, increment, gains, left, right);
+        }
+    });
+
+    let mut bank = Bank::new();
+    let recursive = time_voice(|phases, increments, left, right| {
+        for (&phase, &increment) in phases.iter().zip(increments) {
+            if bank.is_full() {
+                bank.flush(left, right);
+            }
+            bank.push(phase, increment, gains);
+        }
+        bank.flush(left, right);
+    });
+
+    println!(
+        "{} partials at {} Hz, voices in real time on one core:",
+        PARTIALS, SAMPLE_RATE
+    );
+    println!("  sin() per sample   {:8.1}", SECONDS as f64 / direct);
+    println!(
+        "  oscillator bank    {:8.1}  ({:.1}x)",
+        SECONDS as f64 / recursive,
+        direct / recursive
+    );
+
+    let mut synth = Synth::new(SAMPLE_RATE as f32);
+    for key in 0..VOICES {
+        synth.channels[0].toggle_key_down(Note::try_from(48 + key).unwrap(), 0.8);
+    }
+
+    let mut left = vec![0.0; 512];
+    let mut right = vec![0.0; 512];
+    let start = Instant::now();
+    for _ in 0..SECONDS * SAMPLE_RATE / left.len() {
+        synth.render_stereo(&mut left, &mut right);
+        black_box((&left, &right));
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!(
+        "  synth, {} voices   {:8.1}  ({:.1}x real time)",
+        VOICES,
+        VOICES as f64 * SECONDS as f64 / elapsed,
+        SECONDS as f64 / elapsed
+    );
+}
@@ -0,0 +1,96 @@
+#[derive(Clone, Copy, PartialEq)]
+pub enum Curve {
+    Linear,
+    Exponential,
+}
+
+impl Curve {
+    /// Maps the progress through a segment, 0.0 to 1.0, onto how far the level has moved.
+    fn shape(self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => x,
+            // Normalized so the segment still ends exactly on its target
+            Curve::Exponential => (1.0 - (-5.0 * x).exp()) / (1.0 - (-5.0f32).exp()),
+        }
+    }
+}
+
+/// Times are in seconds, the sustain level is 0.0 to 1.0.
+#[derive(Clone, Copy)]
+pub struct Adsr {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+    pub curve: Curve,
+}
+
+fn segment(curve: Curve, from: f32, to: f32, t: f32, duration: f32) -> f32 {
+    if duration <= 0.0 {
+        return to;
+    }
+    from + (to - from) * curve.shape(t / duration)
+}
+
+impl Adsr {
+    /// Level `t` seconds after the key went down, while it is still held.
+    pub fn held(&self, t: f32) -> f32 {
+        if t < self.attack {
+            segment(self.curve, 0.0, 1.0, t, self.attack)
+        } else if t < self.attack + self.decay {
+            segment(self.curve, 1.0, self.sustain, t - self.attack, self.decay)
+        } else {
+            self.sustain
+        }
+    }
+
+    /// Level `t` seconds after the key went down. A release starts from wherever the envelope
+    /// was at the time, so letting go during the attack doesn't jump.
+    pub fn evaluate(&self, t: f32, since_release: Option<f32>) -> f32 {
+        match since_release {
+            Some(r) => segment(self.curve, self.held(t - r), 0.0, r, self.release),
+            None => self.held(t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adsr(curve: Curve) -> Adsr {
+        Adsr {
+            attack: 0.1,
+            decay: 0.2,
+            sustain: 0.4,
+            release: 0.5,
+            curve,
+        }
+    }
+
+    #[test]
+    fn decays_to_the_sustain_level() {
+        for &curve in &[Curve::Linear, Curve::Exponential] {
+            let adsr = adsr(curve);
+            let halfway = adsr.held(adsr.attack + adsr.decay / 2.0);
+            assert!(adsr.sustain < halfway && halfway < 1.0);
+            assert!((adsr.held(adsr.attack + adsr.decay) - adsr.sustain).abs() < 1e-6);
+            assert_eq!(adsr.held(10.0), adsr.sustain);
+        }
+    }
+
+    #[test]
+    fn releases_from_the_current_level() {
+        for &curve in &[Curve::Linear, Curve::Exponential] {
+            let adsr = adsr(curve);
+            // Let go halfway through the attack
+            let t = adsr.attack / 2.0;
+            let level = adsr.held(t);
+            assert!(level > 0.0 && level < 1.0 && level != adsr.sustain);
+            assert_eq!(adsr.evaluate(t, Some(0.0)), level);
+            assert!(adsr.evaluate(t + adsr.release / 2.0, Some(adsr.release / 2.0)) < level);
+            assert_eq!(adsr.evaluate(t + adsr.release, Some(adsr.release)), 0.0);
+        }
+    }
+}
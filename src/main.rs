@@ -3,64 +3,197 @@ extern crate cpal;
 extern crate midir;
 extern crate rustfft;
 
-use std::{
-    sync::{Arc, Mutex},
-    thread,
-    time::Instant,
-};
-
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
+use std::time::Instant;
 
-use audio::setup_audio;
+use audio::{AudioEngine, AudioSettings};
 use imgui::*;
-use midi::{setup_midi, MidiEvent};
+use midi::{setup_midi, Track};
+use patch::Patch;
+use player::Player;
+use presets::PRESETS;
 use rustfft::num_traits::Zero;
 use rustfft::{num_complex::Complex, FFT};
-use synth::Synth;
+use smf::Smf;
+use synth::{Synth, CHANNELS};
+use ui::audio_panel::draw_audio_panel;
+use ui::envelope_editor::EnvelopeEditor;
+use ui::file_panel::{FileAction, FilePanel};
 use ui::midi_drawer::draw_midi_viewer;
-use wmidi::MidiMessage;
+use ui::partial_editor::PartialEditor;
+use ui::patch_browser::PatchBrowser;
+use ui::recorder::Recorder;
+use ui::transport::draw_transport;
 
+mod analysis;
 mod audio;
+mod bench;
+mod envelope;
 mod midi;
+mod offline;
+mod oscillator;
+mod patch;
+mod patch_file;
+mod player;
+mod presets;
+mod remote;
 mod ringbuffer;
+mod smf;
 mod support;
 mod synth;
+mod wav;
 
 mod ui;
 
-fn main() {
-    let mut conns = setup_midi().unwrap();
+/// Directory for settings and patches, `music-box` in the platform's config directory.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
 
-    let (tx, rx) = mpsc::channel::<MidiEvent>();
+    Some(base.join("music-box"))
+}
 
-    let synth = Arc::new(Mutex::new(Synth::new(48_000.0)));
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+
+    // Headless mode, e.g. music-box --render song.mid song.wav --sample-rate 44100
+    if let Some(idx) = args.iter().position(|a| a == "--render") {
+        let (input, output) = match (args.get(idx + 1), args.get(idx + 2)) {
+            (Some(input), Some(output)) => (input, output),
+            _ => {
+                eprintln!("usage: --render <input.mid> <output.wav> [--sample-rate <hz>]");
+                std::process::exit(1);
+            }
+        };
+
+        let sample_rate = match args.iter().position(|a| a == "--sample-rate") {
+            Some(idx) => match args.get(idx + 1).and_then(|s| s.parse().ok()) {
+                Some(sample_rate) => sample_rate,
+                None => {
+                    eprintln!("--sample-rate expects a number");
+                    std::process::exit(1);
+                }
+            },
+            None => 48_000,
+        };
 
-    let audio_synth = synth.clone();
+        if let Err(e) = offline::render_midi_file(Path::new(input), Path::new(output), sample_rate)
+        {
+            eprintln!("failed to render {}: {}", input, e);
+            std::process::exit(1);
+        }
 
-    thread::spawn(move || {
-        setup_audio(&audio_synth);
+        return;
+    }
 
-        loop {
-            if let Ok(e) = rx.recv() {
-                match e.input {
-                    MidiMessage::NoteOff(_, n, _) => {
-                        audio_synth.lock().unwrap().toggle_key_up(n);
-                    }
-                    MidiMessage::NoteOn(_, n, v) => {
-                        let vel = u8::from(v) as f32 / 127.0;
-                        audio_synth.lock().unwrap().toggle_key_down(n, vel);
-                    }
-                    _ => {
-                        panic!();
+    // Turns a recorded note into a patch, e.g. music-box --analyze note.wav note.patch
+    if let Some(idx) = args.iter().position(|a| a == "--analyze") {
+        let (input, output) = match (args.get(idx + 1), args.get(idx + 2)) {
+            (Some(input), Some(output)) => (input, output),
+            _ => {
+                eprintln!("usage: --analyze <input.wav> <output.patch> [--envelopes]");
+                std::process::exit(1);
+            }
+        };
+
+        let envelopes = args.iter().any(|a| a == "--envelopes");
+        let result = analysis::analyze_file(Path::new(input), envelopes).and_then(|analysis| {
+            println!("fundamental {:.2} Hz", analysis.fundamental);
+            patch_file::save(Path::new(output), &analysis.patch)
+        });
+        if let Err(e) = result {
+            eprintln!("failed to analyze {}: {}", input, e);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    // cargo run --release -- --bench
+    if args.iter().any(|a| a == "--bench") {
+        bench::run();
+        return;
+    }
+
+    if args.iter().any(|a| a == "--list-devices") {
+        for name in audio::host_names() {
+            println!("{}", name);
+            match audio::open_host(Some(name)).and_then(|host| audio::output_devices(&host)) {
+                Ok(devices) => {
+                    for device in devices {
+                        let rates = device
+                            .sample_rates
+                            .iter()
+                            .map(|r| r.to_string())
+                            .collect::<Vec<_>>();
+                        print!("  {}  [{} Hz]", device.name, rates.join(", "));
+                        if let Some((min, max)) = device.buffer_sizes {
+                            print!("  buffer {}..{}", min, max);
+                        }
+                        println!();
                     }
                 }
+                Err(e) => println!("  {}", e),
             }
         }
-    });
+        return;
+    }
+
+    // Saved choices, overridden by e.g. --host alsa --device default --buffer-size 256
+    let mut audio_settings = AudioSettings::load();
+    if let Err(e) = audio_settings.apply_args(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let (live_tx, live_rx) = mpsc::channel();
+    let mut conns = setup_midi(live_tx).unwrap();
+
+    let (mut remote, processor, live) = remote::split(Synth::new(48_000.0), Player::new());
+    live.spawn(live_rx);
+
+    let mut engine = AudioEngine::new(processor, audio_settings);
+    if let Err(e) = engine.start() {
+        engine.error = Some(format!("failed to open the audio device: {}", e));
+    }
+
+    // The UI edits its own copies and sends one over whenever it changes
+    let mut patches = vec![Patch::new(); CHANNELS];
+    let mut program_changes = [0; CHANNELS];
 
     let system = support::init(file!());
 
-    let mut notes = vec![];
+    let mut notes = Track {
+        name: String::from("live"),
+        events: vec![],
+    };
+
+    let mut file_panel = FilePanel::new();
+    let mut recorder = Recorder::new();
+    let mut envelope_editor = EnvelopeEditor::new();
+    let mut partial_editor = PartialEditor::new();
+    let mut patch_browser = PatchBrowser::new();
+
+    // Tracks imported from a file, shown instead of the live input while loaded
+    let mut song = None;
+
+    if let Some(path) = args
+        .iter()
+        .skip(1)
+        .find(|a| a.ends_with(".mid") || a.ends_with(".midi"))
+    {
+        match Smf::read(Path::new(path)) {
+            Ok(smf) => {
+                let tracks = smf.timeline();
+                remote.load(&tracks);
+                song = Some(tracks);
+            }
+            Err(e) => file_panel.error = Some(format!("failed to open {}: {}", path, e)),
+        }
+    }
 
     let mut current_time = 0;
 
@@ -69,10 +202,6 @@ fn main() {
     let mut frequencies = vec![0.0; 4096 * 16];
     let mut frequency_index = 0;
 
-    let ui_synth = synth.clone();
-
-    let mut last_samples = 0;
-
     let fft = rustfft::algorithm::Radix4::<f32>::new(frequencies.len(), false);
 
     let mut averages = vec![0.0; frequencies.len() / 2];
@@ -82,28 +211,29 @@ fn main() {
         last_tick = Instant::now();
 
         for conn in &mut conns {
-            if let Ok(e) = conn.rx.try_recv() {
+            while let Ok(e) = conn.rx.try_recv() {
                 conn.input.push(e.clone());
-                notes.push(e.clone());
+                notes.events.push(e.clone());
                 current_time = e.time;
-                tx.send(e).unwrap();
             }
         }
 
-        if let Ok(synth) = ui_synth.lock() {
-            let buffer = synth.sample_buffer();
-            let samples = synth.samples();
+        engine.poll();
+        remote.update();
 
-            for s in 0..samples - last_samples {
-                frequency_index = frequency_index % frequencies.len();
-                frequencies[frequency_index] = buffer
-                    .get((samples - last_samples - s - 1) as usize)
-                    .cloned()
-                    .unwrap(); // TODO: Is this a good idea?
-                frequency_index += 1;
+        // Program changes switch the audio thread to a preset, follow along in the editor
+        for (channel, status) in remote.status.channels.iter().enumerate() {
+            if status.program_changes != program_changes[channel] {
+                program_changes[channel] = status.program_changes;
+                if let Some(preset) = PRESETS.get(status.controllers.program as usize) {
+                    patches[channel] = preset.patch();
+                }
             }
+        }
 
-            last_samples = samples;
+        for sample in remote.scope_samples() {
+            frequencies[frequency_index] = sample;
+            frequency_index = (frequency_index + 1) % frequencies.len();
         }
 
         let mut freqs = (0..frequencies.len())
@@ -133,14 +263,83 @@ fn main() {
             .position([0.0, 0.0], Condition::Always)
             .size([midi_win_width, midi_win_height], Condition::Always)
             .no_decoration()
-            .build(ui, || {
-                draw_midi_viewer(ui, &notes, current_time, midi_win_width, midi_win_height);
+            .bring_to_front_on_focus(false)
+            .build(ui, || match &song {
+                Some(tracks) => {
+                    let end = tracks
+                        .iter()
+                        .filter_map(|t| t.events.last())
+                        .map(|e| e.time)
+                        .max()
+                        .unwrap_or(0);
+
+                    let position = remote.status.player.position;
+
+                    draw_midi_viewer(
+                        ui,
+                        tracks,
+                        0,
+                        end,
+                        Some(position),
+                        midi_win_width,
+                        midi_win_height,
+                    );
+                }
+                None => {
+                    if let Some(first) = notes.events.first() {
+                        draw_midi_viewer(
+                            ui,
+                            std::slice::from_ref(&notes),
+                            first.time,
+                            current_time,
+                            None,
+                            midi_win_width,
+                            midi_win_height,
+                        );
+                    }
+                }
             });
 
+        match file_panel.draw(ui) {
+            Some(FileAction::Open(path)) => match Smf::read(&path) {
+                Ok(smf) => {
+                    let tracks = smf.timeline();
+                    remote.load(&tracks);
+                    song = Some(tracks);
+                    file_panel.error = None;
+                }
+                Err(e) => {
+                    file_panel.error = Some(format!("failed to open {}: {}", path.display(), e))
+                }
+            },
+            Some(FileAction::ShowLive) => {
+                remote.load(&[]);
+                song = None;
+            }
+            None => {}
+        }
+
+        if let Some(take) = recorder.draw(ui, &mut conns) {
+            remote.load(&take);
+            song = Some(take);
+        }
+
+        if song.is_some() {
+            draw_transport(ui, &mut remote);
+        }
+
+        draw_audio_panel(ui, &mut engine);
+
+        let channel = partial_editor.channel();
+        if patch_browser.draw(ui, channel, &mut patches[channel]) {
+            remote.set_patch(channel, &patches[channel]);
+        }
+
         Window::new(im_str!("oscilloscope"))
             .position([0.0, midi_win_height], Condition::Always)
             .size([midi_win_width, midi_win_height], Condition::Always)
             .no_decoration()
+            .bring_to_front_on_focus(false)
             .build(ui, || {
                 let draw_list = ui.get_window_draw_list();
 
@@ -172,6 +371,7 @@ fn main() {
             .position([midi_win_width, midi_win_height], Condition::Always)
             .size([midi_win_width, midi_win_height], Condition::Always)
             .no_decoration()
+            .bring_to_front_on_focus(false)
             .build(ui, || {
                 let draw_list = ui.get_window_draw_list();
 
@@ -224,37 +424,31 @@ fn main() {
             .position([midi_win_width, 0.0], Condition::Always)
             .size([midi_win_width, midi_win_height], Condition::Always)
             .no_decoration()
+            .bring_to_front_on_focus(false)
             .build(ui, || {
-                let draw_list = ui.get_window_draw_list();
-                for (partial, &partial_volume) in synth.lock().unwrap().partials.iter().enumerate()
-                {
-                    draw_list
-                        .add_rect(
-                            [
-                                midi_win_width + partial as f32 * midi_win_width / 64.0,
-                                (1.0 - partial_volume) * midi_win_height,
-                            ],
-                            [
-                                midi_win_width + (partial + 1) as f32 * midi_win_width / 64.0,
-                                midi_win_height,
-                            ],
-                            [1.0, 1.0, 1.0],
-                        )
-                        .build();
+                if partial_editor.draw(
+                    ui,
+                    &mut patches,
+                    &remote.status,
+                    [midi_win_width, 0.0],
+                    [midi_win_width, midi_win_height],
+                ) {
+                    let channel = partial_editor.channel();
+                    remote.set_patch(channel, &patches[channel]);
                 }
             });
 
-        let [p_x, p_y] = ui.io().mouse_pos;
-        if ui.is_mouse_down(MouseButton::Left)
-            && p_x > midi_win_width
-            && p_x < midi_win_width * 2.0
-            && p_y > 0.0
-            && p_y < midi_win_height
-        {
-            let partial = (64.0 * (p_x - midi_win_width) / midi_win_width) as usize;
-            let p_vol = 1.0 - p_y / midi_win_height;
-
-            synth.lock().unwrap().partials[partial] = p_vol;
-        }
+        Window::new(im_str!("partial"))
+            .position(
+                [midi_win_width + 10.0, midi_win_height + 10.0],
+                Condition::FirstUseEver,
+            )
+            .always_auto_resize(true)
+            .build(ui, || {
+                if partial_editor.draw_selection(ui, &mut envelope_editor, &mut patches) {
+                    let channel = partial_editor.channel();
+                    remote.set_patch(channel, &patches[channel]);
+                }
+            });
     });
 }
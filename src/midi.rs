@@ -11,14 +11,26 @@ pub struct MidiEvent {
     pub time: u64,
 }
 
+/// A named sequence of events, either recorded live or imported from a file.
+#[derive(Clone)]
+pub struct Track {
+    pub name: String,
+    pub events: Vec<MidiEvent>,
+}
+
 pub struct MidiSource {
-    _connection: MidiInputConnection<mpsc::Sender<MidiEvent>>,
+    pub name: String,
+    _connection: MidiInputConnection<(mpsc::Sender<MidiEvent>, mpsc::Sender<MidiMessage<'static>>)>,
     pub rx: mpsc::Receiver<MidiEvent>,
     _tx: mpsc::Sender<MidiEvent>,
     pub input: Vec<MidiEvent>,
 }
 
-pub fn setup_midi() -> Result<Vec<MidiSource>, Box<dyn Error>> {
+/// Connects to every input port. Messages go to the `rx` of their port for the UI, and to
+/// `live` to be played.
+pub fn setup_midi(
+    live: mpsc::Sender<MidiMessage<'static>>,
+) -> Result<Vec<MidiSource>, Box<dyn Error>> {
     let mut midi_in = MidiInput::new("midir reading input")?;
     midi_in.ignore(Ignore::None);
 
@@ -31,27 +43,29 @@ pub fn setup_midi() -> Result<Vec<MidiSource>, Box<dyn Error>> {
         midi.ignore(Ignore::None);
         let (tx, rx) = mpsc::channel();
         conns.push(MidiSource {
+            name: midi.port_name(&port)?,
             _connection: midi.connect(
                 &port,
                 "midir-read-input",
-                move |stamp, bytes, tx| {
-                    let message = wmidi::MidiMessage::try_from(bytes).unwrap().to_owned();
-
-                    match message {
-                        MidiMessage::NoteOff(_, _, _) => {}
-                        MidiMessage::NoteOn(_, _, _) => {}
-                        _ => {
-                            return;
-                        }
+                move |stamp, bytes, (tx, live)| {
+                    let message = match wmidi::MidiMessage::try_from(bytes) {
+                        Ok(message) => message.to_owned(),
+                        Err(_) => return,
+                    };
+
+                    // Only channel messages, system messages such as clock and sysex are dropped
+                    if message.channel().is_none() {
+                        return;
                     }
 
+                    live.send(message.clone()).unwrap();
                     tx.send(MidiEvent {
                         input: message,
                         time: stamp,
                     })
                     .unwrap();
                 },
-                tx.clone(),
+                (tx.clone(), live.clone()),
             )?,
             rx,
             _tx: tx,
@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use crate::smf::Smf;
+use crate::synth::Synth;
+use crate::wav;
+
+/// Upper bound on how long released notes are allowed to ring out after the last event.
+const MAX_TAIL_SECONDS: usize = 10;
+
+/// Frames rendered at a time while waiting for the tails to end.
+const TAIL_BLOCK: usize = 256;
+
+/// Renders a Standard MIDI File through `Synth` without touching any audio device.
+pub fn render_midi_file(
+    input: &Path,
+    output: &Path,
+    sample_rate: u32,
+) -> Result<(), anyhow::Error> {
+    let smf = Smf::read(input)?;
+
+    let mut synth = Synth::new(sample_rate as f32);
+
+    let events = smf.merged_timeline();
+    let frame = |time: u64| (time * sample_rate as u64 / 1_000_000) as usize;
+    let length = events.last().map(|e| frame(e.time)).unwrap_or(0);
+
+    // Interleaved stereo
+    let mut samples = vec![0.0; length * 2];
+    synth.render_with_events(
+        &mut samples,
+        2,
+        events.iter().map(|e| (frame(e.time), &e.input)),
+    );
+
+    let mut block = vec![0.0; TAIL_BLOCK * 2];
+    let max_len = samples.len() + MAX_TAIL_SECONDS * sample_rate as usize * 2;
+    while !synth.is_silent() && samples.len() < max_len {
+        synth.render_interleaved(&mut block, 2);
+        samples.extend_from_slice(&block);
+    }
+
+    wav::write(output, sample_rate, 2, &samples)
+}
@@ -0,0 +1,234 @@
+use std::f64::consts::PI;
+
+/// Oscillators rendered side by side. The inner loop runs over fixed size arrays of this
+/// length, which the compiler turns into SIMD instructions.
+pub const LANES: usize = 8;
+
+/// Sine and cosine of a phase in cycles, evaluated in f64 so large phases stay accurate.
+fn sin_cos(cycles: f64) -> (f32, f32) {
+    let (sin, cos) = (2.0 * PI * cycles).sin_cos();
+    (sin as f32, cos as f32)
+}
+
+/// A bank of recursive sine oscillators. Instead of calling `sin()` every sample, each
+/// oscillator rotates a (sin, cos) pair by a fixed angle per frame, which costs four
+/// multiplications. The pairs are seeded from the exact phase at the start of every block, so
+/// rounding errors can't build up beyond a single block.
+pub struct Bank {
+    sin: [f32; LANES],
+    cos: [f32; LANES],
+    /// Rotation per frame.
+    step_sin: [f32; LANES],
+    step_cos: [f32; LANES],
+    gain_left: [f32; LANES],
+    gain_right: [f32; LANES],
+    /// Gains reached after the last frame, for oscillators that fade from one level to another.
+    end_left: [f32; LANES],
+    end_right: [f32; LANES],
+    len: usize,
+}
+
+impl Bank {
+    pub fn new() -> Self {
+        Bank {
+            sin: [0.0; LANES],
+            cos: [0.0; LANES],
+            step_sin: [0.0; LANES],
+            step_cos: [0.0; LANES],
+            gain_left: [0.0; LANES],
+            gain_right: [0.0; LANES],
+            end_left: [0.0; LANES],
+            end_right: [0.0; LANES],
+            len: 0,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == LANES
+    }
+
+    /// Adds an oscillator at `phase` that moves `increment` cycles per frame, both in cycles.
+    pub fn push(&mut self, phase: f64, increment: f64, gains: [f32; 2]) {
+        self.push_ramp(phase, increment, gains, gains);
+    }
+
+    /// Like `push`, with gains that move in a straight line from `from` at the first frame to
+    /// `to` after the last one.
+    pub fn push_ramp(&mut self, phase: f64, increment: f64, from: [f32; 2], to: [f32; 2]) {
+        let lane = self.len;
+        let (sin, cos) = sin_cos(phase);
+        let (step_sin, step_cos) = sin_cos(increment);
+
+        self.sin[lane] = sin;
+        self.cos[lane] = cos;
+        self.step_sin[lane] = step_sin;
+        self.step_cos[lane] = step_cos;
+        self.gain_left[lane] = from[0];
+        self.gain_right[lane] = from[1];
+        self.end_left[lane] = to[0];
+        self.end_right[lane] = to[1];
+        self.len += 1;
+    }
+
+    /// Adds the sum of all oscillators into `left` and `right`, and empties the bank.
+    pub fn flush(&mut self, left: &mut [f32], right: &mut [f32]) {
+        if self.len == 0 {
+            return;
+        }
+
+        // Unused lanes are silent
+        for lane in self.len..LANES {
+            self.gain_left[lane] = 0.0;
+            self.gain_right[lane] = 0.0;
+            self.end_left[lane] = 0.0;
+            self.end_right[lane] = 0.0;
+        }
+
+        let frames = left.len() as f32;
+        let (mut gain_left, mut gain_right) = (self.gain_left, self.gain_right);
+        let mut step_left = [0.0; LANES];
+        let mut step_right = [0.0; LANES];
+        for lane in 0..LANES {
+            step_left[lane] = (self.end_left[lane] - gain_left[lane]) / frames;
+            step_right[lane] = (self.end_right[lane] - gain_right[lane]) / frames;
+        }
+
+        let (mut sin, mut cos) = (self.sin, self.cos);
+        for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+            let mut sum_left = 0.0;
+            let mut sum_right = 0.0;
+            for lane in 0..LANES {
+                sum_left += sin[lane] * gain_left[lane];
+                sum_right += sin[lane] * gain_right[lane];
+                gain_left[lane] += step_left[lane];
+                gain_right[lane] += step_right[lane];
+
+                let s = sin[lane] * self.step_cos[lane] + cos[lane] * self.step_sin[lane];
+                let c = cos[lane] * self.step_cos[lane] - sin[lane] * self.step_sin[lane];
+                sin[lane] = s;
+                cos[lane] = c;
+            }
+            *l += sum_left;
+            *r += sum_right;
+        }
+
+        self.len = 0;
+    }
+}
+
+/// A single recursive oscillator with its own level per frame, for partials that don't follow
+/// the voice envelope.
+pub fn render_one(
+    phase: f64,
+    increment: f64,
+    gains: [f32; 2],
+    levels: &[f32],
+    left: &mut [f32],
+    right: &mut [f32],
+) {
+    let (mut sin, mut cos) = sin_cos(phase);
+    let (step_sin, step_cos) = sin_cos(increment);
+
+    for ((l, r), level) in left.iter_mut().zip(right.iter_mut()).zip(levels) {
+        let sample = sin * level;
+        *l += sample * gains[0];
+        *r += sample * gains[1];
+
+        let s = sin * step_cos + cos * step_sin;
+        cos = cos * step_cos - sin * step_sin;
+        sin = s;
+    }
+}
+
+/// Calls `sin()` for every sample, the way partials were rendered before the bank. Kept as a
+/// reference for the benchmark and the tests.
+pub fn render_direct(
+    mut phase: f64,
+    increment: f64,
+    gains: [f32; 2],
+    left: &mut [f32],
+    right: &mut [f32],
+) {
+    for (l, r) in left.iter_mut().zip(right.iter_mut()) {
+        let sample = (2.0 * std::f32::consts::PI * phase as f32).sin();
+        *l += sample * gains[0];
+        *r += sample * gains[1];
+
+        phase += increment;
+        phase -= phase.floor();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bank_matches_direct_sine() {
+        let frames = 64;
+        let (mut left, mut right) = (vec![0.0; frames], vec![0.0; frames]);
+        let (mut expected_left, mut expected_right) = (vec![0.0; frames], vec![0.0; frames]);
+
+        let mut bank = Bank::new();
+        for partial in 1..=11 {
+            let phase = 0.1 * partial as f64;
+            let increment = 440.0 * partial as f64 / 48_000.0;
+            let gains = [1.0 / partial as f32, 0.5];
+
+            if bank.is_full() {
+                bank.flush(&mut left, &mut right);
+            }
+            bank.push(phase, increment, gains);
+            render_direct(
+                phase,
+                increment,
+                gains,
+                &mut expected_left,
+                &mut expected_right,
+            );
+        }
+        bank.flush(&mut left, &mut right);
+
+        for i in 0..frames {
+            assert!((left[i] - expected_left[i]).abs() < 1e-4);
+            assert!((right[i] - expected_right[i]).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn bank_ramps_gains() {
+        let frames = 64;
+        let (mut left, mut right) = (vec![0.0; frames], vec![0.0; frames]);
+        let (mut expected_left, mut expected_right) = (vec![0.0; frames], vec![0.0; frames]);
+
+        let mut bank = Bank::new();
+        bank.push_ramp(0.25, 0.01, [0.0, 1.0], [1.0, 0.0]);
+        bank.flush(&mut left, &mut right);
+
+        let levels = (0..frames)
+            .map(|i| i as f32 / frames as f32)
+            .collect::<Vec<_>>();
+        render_one(
+            0.25,
+            0.01,
+            [1.0, 0.0],
+            &levels,
+            &mut expected_left,
+            &mut expected_right,
+        );
+        let falling = levels.iter().map(|l| 1.0 - l).collect::<Vec<_>>();
+        render_one(
+            0.25,
+            0.01,
+            [0.0, 1.0],
+            &falling,
+            &mut expected_left,
+            &mut expected_right,
+        );
+
+        for i in 0..frames {
+            assert!((left[i] - expected_left[i]).abs() < 1e-4);
+            assert!((right[i] - expected_right[i]).abs() < 1e-4);
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use crate::envelope::{Adsr, Curve};
+
+/// Upper limit for `Patch::polyphony`.
+pub const MAX_POLYPHONY: usize = 64;
+
+/// Most partials a patch has, files with more are rejected. The audio thread keeps room for
+/// this many in its patches and in every voice, so a program change can copy in any preset
+/// without allocating.
+pub const MAX_PARTIALS: usize = 64;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum VoiceMode {
+    Poly,
+    /// One voice, every new note restarts the envelope.
+    Mono,
+    /// One voice, overlapping notes only change its pitch.
+    Legato,
+}
+
+/// Which of the held keys a monophonic patch plays.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Priority {
+    Last,
+    Low,
+    High,
+}
+
+/// Which voice makes room when a note is played with all voices in use.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stealing {
+    Oldest,
+    Quietest,
+    /// A voice already playing the same key, otherwise the oldest.
+    SameNote,
+    /// The oldest released voice, otherwise the oldest.
+    ReleasedFirst,
+}
+
+/// What moves a patch from its partials towards its morph partials.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MorphSource {
+    Velocity,
+    ModWheel,
+    /// Channel or polyphonic aftertouch, whichever is higher.
+    Aftertouch,
+    /// A sine starting at the first spectrum with every note, at `Patch::morph_rate`.
+    Lfo,
+}
+
+/// Footages of the nine drawbars from left to right, 16' to 1', as ratios to the 8' fundamental.
+pub const DRAWBAR_RATIOS: [f32; 9] = [0.5, 1.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0];
+
+/// Drawbar partials are stored divided by this, which keeps the 1' drawbar, the ninth partial,
+/// within 1.0 after the synth's 1/n scaling is undone.
+pub const DRAWBAR_SCALE: f32 = 9.0;
+
+/// Amplitude of a drawbar pulled out to `level`, 0 to 8. Each step is 3 dB.
+pub fn drawbar_amplitude(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        10.0f32.powf(-3.0 * (8 - level.min(8)) as f32 / 20.0)
+    }
+}
+
+/// Percussion like a Hammond organ's: the second or third harmonic sounds with a quick decay at
+/// the start of a note. It only triggers when no other key is held, so legato playing doesn't
+/// repeat it.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Percussion {
+    /// The third harmonic, 2 2/3', instead of the second, 4'.
+    pub third: bool,
+    pub soft: bool,
+    pub fast: bool,
+}
+
+impl Percussion {
+    pub fn ratio(&self) -> f32 {
+        if self.third {
+            3.0
+        } else {
+            2.0
+        }
+    }
+
+    /// Level relative to a fully pulled out drawbar.
+    pub fn level(&self) -> f32 {
+        if self.soft {
+            0.5
+        } else {
+            1.0
+        }
+    }
+
+    /// Seconds until the percussion has died down by 60 dB.
+    pub fn decay(&self) -> f32 {
+        if self.fast {
+            0.2
+        } else {
+            1.0
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Partial {
+    pub amplitude: f32,
+    /// Frequency relative to the fundamental, the partial number for a harmonic spectrum.
+    pub ratio: f32,
+    /// In cents, on top of the ratio.
+    pub detune: f32,
+    /// Replaces the patch envelope for this partial only, e.g. to let upper harmonics decay
+    /// faster than the fundamental.
+    pub envelope: Option<Adsr>,
+}
+
+impl Partial {
+    /// Frequency relative to the fundamental, including detune.
+    pub fn frequency_ratio(&self) -> f32 {
+        self.ratio * 2.0f32.powf(self.detune / 1200.0)
+    }
+}
+
+/// Everything that defines the sound of the synth, independent of what is being played.
+#[derive(Clone)]
+pub struct Patch {
+    pub partials: Vec<Partial>,
+    pub envelope: Adsr,
+    pub volume: f32,
+    /// -1.0 is hard left, 1.0 hard right.
+    pub pan: f32,
+    /// How far voices are panned by key position, low keys to the left for positive values.
+    pub key_tracking: f32,
+    /// 0.0 to 1.0, spreads the partials alternately left and right.
+    pub spread: f32,
+    /// Scales the partials down with the Lanczos sigma factor, which smoothes out the ringing
+    /// of sharp waveforms like saw and square at the cost of some brightness.
+    pub smoothing: bool,
+    /// Maximum number of voices sounding at once.
+    pub polyphony: usize,
+    pub stealing: Stealing,
+    pub mode: VoiceMode,
+    pub priority: Priority,
+    /// Portamento time in seconds between the notes of a monophonic patch.
+    pub glide: f32,
+    /// Drawbar levels, 16' to 1', when the partials were set up by `set_drawbars`.
+    pub drawbars: Option<[u8; 9]>,
+    /// 0.0 to 1.0, level of the burst of noise at the start of every note.
+    pub click: f32,
+    pub percussion: Option<Percussion>,
+    /// Turns on morphing from `partials` to `morph_partials`.
+    pub morph: Option<MorphSource>,
+    /// The second spectrum, matched one for one with `partials`. Their envelopes are used for
+    /// both, the envelopes here are ignored.
+    pub morph_partials: Vec<Partial>,
+    /// Of the morph LFO, in Hz.
+    pub morph_rate: f32,
+}
+
+impl Patch {
+    pub fn new() -> Self {
+        Patch {
+            partials: (1..=MAX_PARTIALS)
+                .map(|n| Partial {
+                    amplitude: 1.0,
+                    ratio: n as f32,
+                    detune: 0.0,
+                    envelope: None,
+                })
+                .collect(),
+            envelope: Adsr {
+                attack: 0.01,
+                decay: 0.4,
+                sustain: 0.5,
+                release: 0.6,
+                curve: Curve::Linear,
+            },
+            volume: 1.0,
+            pan: 0.0,
+            key_tracking: 0.0,
+            spread: 0.0,
+            smoothing: true,
+            polyphony: 32,
+            stealing: Stealing::ReleasedFirst,
+            mode: VoiceMode::Poly,
+            priority: Priority::Last,
+            glide: 0.0,
+            drawbars: None,
+            click: 0.0,
+            percussion: None,
+            morph: None,
+            morph_partials: vec![],
+            morph_rate: 1.0,
+        }
+    }
+
+    /// Turns this patch into a copy of `other`, reusing the memory of the partials.
+    pub fn copy_from(&mut self, other: &Patch) {
+        let mut partials = std::mem::take(&mut self.partials);
+        partials.clear();
+        partials.extend_from_slice(&other.partials);
+        let mut morph_partials = std::mem::take(&mut self.morph_partials);
+        morph_partials.clear();
+        morph_partials.extend_from_slice(&other.morph_partials);
+
+        *self = Patch {
+            partials,
+            morph_partials,
+            ..*other
+        };
+    }
+
+    /// Gives the morph partials the same length as the partials again, after the partials were
+    /// replaced. New ones start out like their counterparts. Returns whether anything changed.
+    pub fn match_morph_partials(&mut self) -> bool {
+        let len = self.partials.len();
+        if self.morph_partials.len() == len {
+            return false;
+        }
+
+        self.morph_partials.truncate(len);
+        let missing = self.morph_partials.len();
+        self.morph_partials
+            .extend_from_slice(&self.partials[missing..]);
+        true
+    }
+
+    /// Amplitude and frequency ratio of the partial at `index`, `position` of the way from
+    /// the first spectrum to the second. Frequencies move evenly in pitch rather than in Hz.
+    pub fn morphed(&self, index: usize, position: f32) -> (f32, f32) {
+        let a = &self.partials[index];
+        let b = match (self.morph, self.morph_partials.get(index)) {
+            (Some(_), Some(b)) if position > 0.0 => b,
+            _ => return (a.amplitude, a.frequency_ratio()),
+        };
+
+        let amplitude = a.amplitude + (b.amplitude - a.amplitude) * position;
+        let ratio =
+            a.frequency_ratio() * (b.frequency_ratio() / a.frequency_ratio()).powf(position);
+        (amplitude, ratio)
+    }
+
+    /// Sets the partials from absolute amplitudes, one per partial. The synth scales partial n by
+    /// 1/n, so the stored amplitudes are relative to a saw, with the loudest at 1.0.
+    pub fn set_amplitudes(&mut self, amplitudes: &[f32]) {
+        let stored = amplitudes
+            .iter()
+            .enumerate()
+            .map(|(i, a)| a * (i + 1) as f32)
+            .collect::<Vec<_>>();
+        let max = stored.iter().cloned().fold(0.0, f32::max);
+
+        for (partial, amplitude) in self.partials.iter_mut().zip(stored) {
+            partial.amplitude = if max > 0.0 { amplitude / max } else { 0.0 };
+        }
+    }
+
+    /// Replaces the partials with the nine drawbars, pulled out to `levels` from 0 to 8.
+    /// Switches morphing off, the drawbars have no second spectrum to morph to.
+    pub fn set_drawbars(&mut self, levels: [u8; 9]) {
+        self.morph = None;
+
+        let mut bars = DRAWBAR_RATIOS.iter().zip(levels).collect::<Vec<_>>();
+        bars.sort_by(|a, b| a.0.total_cmp(b.0));
+
+        self.partials = bars
+            .iter()
+            .enumerate()
+            .map(|(i, &(&ratio, level))| Partial {
+                amplitude: drawbar_amplitude(level) * (i + 1) as f32 / DRAWBAR_SCALE,
+                ratio,
+                detune: 0.0,
+                envelope: None,
+            })
+            .collect();
+        self.drawbars = Some(levels);
+        // Meant for long harmonic series, it would only dull the upper drawbars
+        self.smoothing = false;
+    }
+
+    /// Resets the partials to exact multiples of the fundamental.
+    pub fn harmonic(&mut self) {
+        for (i, partial) in self.partials.iter_mut().enumerate() {
+            partial.ratio = (i + 1) as f32;
+            partial.detune = 0.0;
+        }
+    }
+
+    /// Stretches the partials like a stiff string, with `b` as the inharmonicity coefficient.
+    /// Partial n ends up at n * sqrt(1 + b * n^2).
+    pub fn stretch(&mut self, b: f32) {
+        for (i, partial) in self.partials.iter_mut().enumerate() {
+            let n = (i + 1) as f32;
+            partial.ratio = n * (1.0 + b * n * n).sqrt();
+        }
+    }
+
+    /// Pan position of a voice playing `key`, before the partials are spread.
+    pub fn voice_pan(&self, key: u8) -> f32 {
+        self.pan + self.key_tracking * (key as f32 - 64.0) / 64.0
+    }
+
+    /// Pan offset of the partial at `index`. The fundamental stays in the center, the higher
+    /// partials move further out, odd ones to the left and even ones to the right.
+    pub fn spread_offset(&self, index: usize) -> f32 {
+        let side = if index % 2 == 1 { 1.0 } else { -1.0 };
+        self.spread * side * (1.0 - 1.0 / (index + 1) as f32)
+    }
+
+    /// The longest release of any audible envelope, after which a released voice is silent.
+    pub fn release(&self) -> f32 {
+        self.partials
+            .iter()
+            .filter(|p| p.amplitude > 0.0)
+            .filter_map(|p| p.envelope.as_ref())
+            .map(|e| e.release)
+            .fold(self.envelope.release, f32::max)
+    }
+
+    /// Gives every partial a copy of the patch envelope, with the decay, sustain and release
+    /// shrinking towards the upper partials by `amount`.
+    pub fn tilt_envelopes(&mut self, amount: f32) {
+        for (i, partial) in self.partials.iter_mut().enumerate() {
+            let scale = 1.0 / (1.0 + amount * i as f32);
+
+            let mut envelope = self.envelope;
+            envelope.decay *= scale;
+            envelope.sustain *= scale;
+            envelope.release *= scale;
+            partial.envelope = Some(envelope);
+        }
+    }
+}
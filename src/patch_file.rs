@@ -0,0 +1,449 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail};
+
+use crate::envelope::{Adsr, Curve};
+use crate::patch::{
+    MorphSource, Partial, Patch, Percussion, Priority, Stealing, VoiceMode, MAX_PARTIALS,
+    MAX_POLYPHONY,
+};
+
+/// Bumped whenever a file written now would be read differently by an older build.
+const VERSION: u32 = 3;
+
+const EXTENSION: &str = "patch";
+
+/// Where patches are kept unless another directory is picked in the browser.
+pub fn default_dir() -> PathBuf {
+    match crate::config_dir() {
+        Some(dir) => dir.join("patches"),
+        None => PathBuf::from("patches"),
+    }
+}
+
+/// Path of the patch called `name` in `dir`. Names can't contain path separators, so the
+/// patch always stays in `dir`.
+pub fn path(dir: &Path, name: &str) -> Result<PathBuf, anyhow::Error> {
+    if name.is_empty() {
+        bail!("a patch needs a name");
+    }
+    if name.contains(['/', '\\']) {
+        bail!("patch names can't contain / or \\");
+    }
+    // Appended rather than set, a name like "Bass 1.5" has a dot of its own
+    Ok(dir.join(format!("{}.{}", name, EXTENSION)))
+}
+
+/// Names of the patches in `dir`, sorted. A missing directory has no patches.
+pub fn list(dir: &Path) -> Result<Vec<String>, anyhow::Error> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut names = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+pub fn load(path: &Path) -> Result<Patch, anyhow::Error> {
+    parse(&fs::read_to_string(path)?)
+}
+
+pub fn save(path: &Path, patch: &Patch) -> Result<(), anyhow::Error> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, format(patch))?;
+    Ok(())
+}
+
+/// Renames a patch in `dir`, refusing to overwrite another one.
+pub fn rename(dir: &Path, from: &str, to: &str) -> Result<(), anyhow::Error> {
+    let target = path(dir, to)?;
+    if target.exists() {
+        bail!("{} already exists", to);
+    }
+    fs::rename(path(dir, from)?, target)?;
+    Ok(())
+}
+
+fn curve_name(curve: Curve) -> &'static str {
+    match curve {
+        Curve::Linear => "linear",
+        Curve::Exponential => "exponential",
+    }
+}
+
+fn stealing_name(stealing: Stealing) -> &'static str {
+    match stealing {
+        Stealing::Oldest => "oldest",
+        Stealing::Quietest => "quietest",
+        Stealing::SameNote => "same-note",
+        Stealing::ReleasedFirst => "released-first",
+    }
+}
+
+fn mode_name(mode: VoiceMode) -> &'static str {
+    match mode {
+        VoiceMode::Poly => "poly",
+        VoiceMode::Mono => "mono",
+        VoiceMode::Legato => "legato",
+    }
+}
+
+fn priority_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Last => "last",
+        Priority::Low => "low",
+        Priority::High => "high",
+    }
+}
+
+fn morph_name(morph: Option<MorphSource>) -> &'static str {
+    match morph {
+        None => "off",
+        Some(MorphSource::Velocity) => "velocity",
+        Some(MorphSource::ModWheel) => "mod-wheel",
+        Some(MorphSource::Aftertouch) => "aftertouch",
+        Some(MorphSource::Lfo) => "lfo",
+    }
+}
+
+fn format_envelope(envelope: &Adsr) -> String {
+    format!(
+        "{} {} {} {} {}",
+        envelope.attack,
+        envelope.decay,
+        envelope.sustain,
+        envelope.release,
+        curve_name(envelope.curve)
+    )
+}
+
+/// Writes a patch as `key = value` lines, one `partial` line per partial in order.
+pub fn format(patch: &Patch) -> String {
+    let mut text = String::from("# music-box patch\n");
+    text += &format!("version = {}\n\n", VERSION);
+
+    text += &format!("volume = {}\n", patch.volume);
+    text += &format!("pan = {}\n", patch.pan);
+    text += &format!("key_tracking = {}\n", patch.key_tracking);
+    text += &format!("spread = {}\n", patch.spread);
+    text += &format!("smoothing = {}\n", patch.smoothing);
+    text += &format!("polyphony = {}\n", patch.polyphony);
+    text += &format!("stealing = {}\n", stealing_name(patch.stealing));
+    text += &format!("mode = {}\n", mode_name(patch.mode));
+    text += &format!("priority = {}\n", priority_name(patch.priority));
+    text += &format!("glide = {}\n\n", patch.glide);
+
+    if let Some(levels) = patch.drawbars {
+        let levels = levels.iter().map(|l| l.to_string()).collect::<String>();
+        text += &format!("drawbars = {}\n", levels);
+    }
+    text += &format!("click = {}\n", patch.click);
+    text += &match patch.percussion {
+        Some(percussion) => format!(
+            "percussion = {} {} {}\n\n",
+            if percussion.third { "third" } else { "second" },
+            if percussion.soft { "soft" } else { "normal" },
+            if percussion.fast { "fast" } else { "slow" }
+        ),
+        None => String::from("percussion = off\n\n"),
+    };
+
+    text += &format!("morph = {}\n", morph_name(patch.morph));
+    text += &format!("morph_rate = {}\n\n", patch.morph_rate);
+
+    text += "# attack decay sustain release curve\n";
+    text += &format!("envelope = {}\n\n", format_envelope(&patch.envelope));
+
+    text += "# amplitude ratio detune, then optionally an envelope of its own\n";
+    for partial in &patch.partials {
+        text += &format!(
+            "partial = {} {} {}",
+            partial.amplitude, partial.ratio, partial.detune
+        );
+        if let Some(envelope) = &partial.envelope {
+            text += &format!(" {}", format_envelope(envelope));
+        }
+        text += "\n";
+    }
+
+    if !patch.morph_partials.is_empty() {
+        text += "\n# the spectrum to morph to, amplitude ratio detune\n";
+        for partial in &patch.morph_partials {
+            text += &format!(
+                "morph_partial = {} {} {}\n",
+                partial.amplitude, partial.ratio, partial.detune
+            );
+        }
+    }
+
+    text
+}
+
+fn parse_number<T: std::str::FromStr>(word: &str) -> Result<T, anyhow::Error> {
+    word.parse()
+        .map_err(|_| anyhow!("expected a number, found {:?}", word))
+}
+
+fn parse_envelope(words: &[&str]) -> Result<Adsr, anyhow::Error> {
+    let (attack, decay, sustain, release, curve) = match words {
+        [attack, decay, sustain, release, curve] => (attack, decay, sustain, release, curve),
+        _ => bail!("expected attack, decay, sustain, release and curve"),
+    };
+
+    Ok(Adsr {
+        attack: parse_number(attack)?,
+        decay: parse_number(decay)?,
+        sustain: parse_number(sustain)?,
+        release: parse_number(release)?,
+        curve: match *curve {
+            "linear" => Curve::Linear,
+            "exponential" => Curve::Exponential,
+            _ => bail!("unknown curve {:?}", curve),
+        },
+    })
+}
+
+fn parse_partial(words: &[&str]) -> Result<Partial, anyhow::Error> {
+    if words.len() < 3 {
+        bail!("expected amplitude, ratio and detune");
+    }
+
+    Ok(Partial {
+        amplitude: parse_number(words[0])?,
+        ratio: parse_number(words[1])?,
+        detune: parse_number(words[2])?,
+        envelope: match &words[3..] {
+            [] => None,
+            envelope => Some(parse_envelope(envelope)?),
+        },
+    })
+}
+
+fn parse_percussion(words: &[&str]) -> Result<Option<Percussion>, anyhow::Error> {
+    let (harmonic, volume, decay) = match words {
+        ["off"] => return Ok(None),
+        [harmonic, volume, decay] => (harmonic, volume, decay),
+        _ => bail!("expected off, or harmonic, volume and decay"),
+    };
+
+    Ok(Some(Percussion {
+        third: match *harmonic {
+            "second" => false,
+            "third" => true,
+            _ => bail!("unknown percussion harmonic {:?}", harmonic),
+        },
+        soft: match *volume {
+            "normal" => false,
+            "soft" => true,
+            _ => bail!("unknown percussion volume {:?}", volume),
+        },
+        fast: match *decay {
+            "slow" => false,
+            "fast" => true,
+            _ => bail!("unknown percussion decay {:?}", decay),
+        },
+    }))
+}
+
+fn parse_line(patch: &mut Patch, key: &str, value: &str) -> Result<(), anyhow::Error> {
+    let words = value.split_whitespace().collect::<Vec<_>>();
+
+    match key {
+        "volume" => patch.volume = parse_number(value)?,
+        "pan" => patch.pan = parse_number(value)?,
+        "key_tracking" => patch.key_tracking = parse_number(value)?,
+        "spread" => patch.spread = parse_number(value)?,
+        "smoothing" => patch.smoothing = value.parse()?,
+        "polyphony" => patch.polyphony = parse_number::<usize>(value)?.clamp(1, MAX_POLYPHONY),
+        "stealing" => {
+            patch.stealing = match value {
+                "oldest" => Stealing::Oldest,
+                "quietest" => Stealing::Quietest,
+                "same-note" => Stealing::SameNote,
+                "released-first" => Stealing::ReleasedFirst,
+                _ => bail!("unknown voice stealing {:?}", value),
+            }
+        }
+        "mode" => {
+            patch.mode = match value {
+                "poly" => VoiceMode::Poly,
+                "mono" => VoiceMode::Mono,
+                "legato" => VoiceMode::Legato,
+                _ => bail!("unknown voice mode {:?}", value),
+            }
+        }
+        "priority" => {
+            patch.priority = match value {
+                "last" => Priority::Last,
+                "low" => Priority::Low,
+                "high" => Priority::High,
+                _ => bail!("unknown note priority {:?}", value),
+            }
+        }
+        "glide" => patch.glide = parse_number(value)?,
+        "drawbars" => {
+            let mut levels = [0; 9];
+            let digits = value.chars().map(|c| c.to_digit(10)).collect::<Vec<_>>();
+            if digits.len() != levels.len() || digits.iter().any(|d| !matches!(d, Some(0..=8))) {
+                bail!("expected nine drawbar levels from 0 to 8, like 888000000");
+            }
+            for (level, digit) in levels.iter_mut().zip(digits) {
+                *level = digit.unwrap_or(0) as u8;
+            }
+            patch.drawbars = Some(levels);
+        }
+        "click" => patch.click = parse_number(value)?,
+        "percussion" => patch.percussion = parse_percussion(&words)?,
+        "morph" => {
+            patch.morph = match value {
+                "off" => None,
+                "velocity" => Some(MorphSource::Velocity),
+                "mod-wheel" => Some(MorphSource::ModWheel),
+                "aftertouch" => Some(MorphSource::Aftertouch),
+                "lfo" => Some(MorphSource::Lfo),
+                _ => bail!("unknown morph source {:?}", value),
+            }
+        }
+        "morph_rate" => patch.morph_rate = parse_number(value)?,
+        "morph_partial" => {
+            if patch.morph_partials.len() == MAX_PARTIALS {
+                bail!("more than {} morph partials", MAX_PARTIALS);
+            }
+            patch.morph_partials.push(parse_partial(&words)?)
+        }
+        "envelope" => patch.envelope = parse_envelope(&words)?,
+        "partial" => {
+            if patch.partials.len() == MAX_PARTIALS {
+                bail!("more than {} partials", MAX_PARTIALS);
+            }
+            patch.partials.push(parse_partial(&words)?)
+        }
+        _ => bail!("unknown setting {:?}", key),
+    }
+
+    Ok(())
+}
+
+/// Reads a patch written by `format`. Settings missing from the file keep their defaults.
+pub fn parse(text: &str) -> Result<Patch, anyhow::Error> {
+    let mut patch = Patch::new();
+    patch.partials.clear();
+    let mut version = None;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("line {}: expected key = value", number + 1))?;
+        let (key, value) = (key.trim(), value.trim());
+
+        if key == "version" {
+            let found = parse_number::<u32>(value)?;
+            if found > VERSION {
+                bail!("written by a newer version, format {} > {}", found, VERSION);
+            }
+            version = Some(found);
+            continue;
+        }
+        if version.is_none() {
+            bail!("not a patch file, the version must come first");
+        }
+
+        parse_line(&mut patch, key, value).map_err(|e| anyhow!("line {}: {}", number + 1, e))?;
+    }
+
+    if version.is_none() {
+        bail!("not a patch file, no version");
+    }
+    if patch.partials.is_empty() {
+        bail!("no partials");
+    }
+
+    Ok(patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let mut patch = Patch::new();
+        patch.partials.truncate(3);
+        patch.partials[1].amplitude = 0.25;
+        patch.partials[2].detune = -7.5;
+        patch.stretch(0.0003);
+        patch.tilt_envelopes(0.1);
+        patch.partials[0].envelope = None;
+        patch.envelope.curve = Curve::Exponential;
+        patch.pan = -0.3;
+        patch.mode = VoiceMode::Legato;
+        patch.stealing = Stealing::Quietest;
+        patch.glide = 0.08;
+        patch.click = 0.25;
+        patch.morph = Some(MorphSource::Lfo);
+        patch.morph_rate = 0.5;
+        patch.morph_partials = patch.partials.clone();
+        patch.morph_partials[0].amplitude = 0.1;
+        patch.percussion = Some(Percussion {
+            third: true,
+            soft: false,
+            fast: true,
+        });
+
+        let read = parse(&format(&patch)).unwrap();
+        assert_eq!(format(&read), format(&patch));
+        assert_eq!(read.partials.len(), 3);
+        assert!(read.partials[0].envelope.is_none());
+        assert!(read.partials[1].envelope.is_some());
+        assert_eq!(read.partials[2].detune, -7.5);
+        assert_eq!(read.morph_partials.len(), 3);
+
+        let mut organ = Patch::new();
+        organ.set_drawbars([8, 8, 6, 0, 0, 0, 0, 0, 1]);
+        let read = parse(&format(&organ)).unwrap();
+        assert_eq!(read.drawbars, organ.drawbars);
+        assert_eq!(format(&read), format(&organ));
+    }
+
+    #[test]
+    fn rejects_newer_versions_and_unknown_settings() {
+        assert!(parse("version = 1\npartial = 1 1 0\n").is_ok());
+        assert!(parse("version = 1\n").is_err());
+        assert!(parse(&format!("version = {}\n", VERSION + 1)).is_err());
+        assert!(parse("volume = 1\n").is_err());
+        assert!(parse("version = 1\nreverb = 0.5\n").is_err());
+
+        let too_many = "version = 1\n".to_string() + &"partial = 1 1 0\n".repeat(MAX_PARTIALS + 1);
+        assert!(parse(&too_many).is_err());
+    }
+
+    #[test]
+    fn names_stay_in_the_directory() {
+        let dir = Path::new("patches");
+        assert_eq!(path(dir, "Bass 1.5").unwrap(), dir.join("Bass 1.5.patch"));
+        assert!(path(dir, "../Bass").is_err());
+        assert!(path(dir, "a\\b").is_err());
+        assert!(path(dir, "").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use crate::midi::{MidiEvent, Track};
+use crate::synth::Synth;
+
+/// Merges tracks into one sequence, ready for `Player::load`.
+pub fn merge(tracks: &[Track]) -> Vec<MidiEvent> {
+    let mut events = tracks
+        .iter()
+        .flat_map(|t| t.events.clone())
+        .collect::<Vec<_>>();
+    events.sort_by_key(|e| e.time);
+    events
+}
+
+/// Range of playback speeds, `Transport::Speed` is clamped to it.
+pub const MIN_SPEED: f32 = 0.25;
+pub const MAX_SPEED: f32 = 2.0;
+
+/// Changes to a `Player` living on the audio thread.
+pub enum Transport {
+    Play,
+    Pause,
+    Stop,
+    Seek(u64),
+    Speed(f32),
+    Loop { enabled: bool, start: u64, end: u64 },
+}
+
+/// Snapshot of a `Player`, for displaying it on another thread.
+#[derive(Clone, Copy)]
+pub struct PlayerStatus {
+    pub position: u64,
+    pub length: u64,
+    pub playing: bool,
+    pub speed: f32,
+    pub looping: bool,
+    pub loop_start: u64,
+    pub loop_end: u64,
+}
+
+/// Plays a sequence of events into a `Synth`. `render` is meant to be called from the audio
+/// thread in place of rendering the synth directly, it splits the block at every event to keep
+/// playback sample accurate.
+pub struct Player {
+    events: Vec<MidiEvent>,
+    /// Index of the next event to be played.
+    next: usize,
+    /// Playback position in microseconds.
+    position: f64,
+    playing: bool,
+    /// Set whenever sounding notes have to be released, handled on the next tick.
+    silence: bool,
+    pub speed: f32,
+    pub looping: bool,
+    pub loop_start: u64,
+    pub loop_end: u64,
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Player {
+            events: vec![],
+            next: 0,
+            position: 0.0,
+            playing: false,
+            silence: false,
+            speed: 1.0,
+            looping: false,
+            loop_start: 0,
+            loop_end: 0,
+        }
+    }
+
+    /// Replaces the sequence and rewinds. The previous sequence is handed back so it can be
+    /// freed outside the audio thread.
+    pub fn load(&mut self, events: Vec<MidiEvent>) -> Vec<MidiEvent> {
+        let previous = std::mem::replace(&mut self.events, events);
+
+        self.loop_start = 0;
+        self.loop_end = self.length();
+        self.stop();
+
+        previous
+    }
+
+    pub fn length(&self) -> u64 {
+        self.events.last().map(|e| e.time).unwrap_or(0)
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position as u64
+    }
+
+    pub fn play(&mut self) {
+        if self.position as u64 >= self.length() {
+            self.seek(0);
+        }
+        self.playing = !self.events.is_empty();
+    }
+
+    pub fn pause(&mut self) {
+        self.playing = false;
+        self.silence = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.pause();
+        self.seek(0);
+    }
+
+    pub fn apply(&mut self, transport: Transport) {
+        match transport {
+            Transport::Play => self.play(),
+            Transport::Pause => self.pause(),
+            Transport::Stop => self.stop(),
+            Transport::Seek(time) => self.seek(time),
+            // At zero or below playback would never get anywhere
+            Transport::Speed(speed) if speed.is_nan() => {}
+            Transport::Speed(speed) => self.speed = speed.clamp(MIN_SPEED, MAX_SPEED),
+            Transport::Loop {
+                enabled,
+                start,
+                end,
+            } => {
+                self.looping = enabled;
+                self.loop_start = start;
+                self.loop_end = end;
+            }
+        }
+    }
+
+    pub fn status(&self) -> PlayerStatus {
+        PlayerStatus {
+            position: self.position(),
+            length: self.length(),
+            playing: self.playing,
+            speed: self.speed,
+            looping: self.looping,
+            loop_start: self.loop_start,
+            loop_end: self.loop_end,
+        }
+    }
+
+    pub fn seek(&mut self, time: u64) {
+        self.position = time as f64;
+        self.next = self.events.partition_point(|e| e.time < time);
+        self.silence = true;
+    }
+
+    /// Renders interleaved frames of `channels` samples through `synth`, applying each event at
+    /// the frame it is due on.
+    pub fn render(&mut self, synth: &mut Synth, out: &mut [f32], channels: usize) {
+        let frames = out.len() / channels;
+
+        let mut done = 0;
+        while done < frames {
+            if self.silence {
+                synth.release_all();
+                synth.reset_controllers();
+                self.silence = false;
+            }
+
+            if !self.playing {
+                synth.render_interleaved(&mut out[done * channels..], channels);
+                return;
+            }
+
+            while let Some(event) = self.events.get(self.next) {
+                if event.time as f64 > self.position {
+                    break;
+                }
+                synth.handle_midi(&event.input);
+                self.next += 1;
+            }
+
+            let looping = self.looping && self.loop_start < self.loop_end;
+            if !looping && self.next >= self.events.len() {
+                self.playing = false;
+                continue;
+            }
+
+            // Render up to whatever happens next, the next event or the end of the loop
+            let step = self.speed as f64 * 1_000_000.0 / synth.sample_rate() as f64;
+            let mut until = self.events.get(self.next).map(|e| e.time as f64);
+            if looping {
+                let end = self.loop_end as f64;
+                until = Some(until.map_or(end, |t| t.min(end)));
+            }
+            let count = match until {
+                Some(time) => {
+                    (((time - self.position) / step).ceil().max(1.0) as usize).min(frames - done)
+                }
+                None => frames - done,
+            };
+
+            synth.render_interleaved(
+                &mut out[done * channels..(done + count) * channels],
+                channels,
+            );
+            self.position += count as f64 * step;
+            done += count;
+
+            if looping && self.position >= self.loop_end as f64 {
+                self.seek(self.loop_start);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wmidi::{Channel, MidiMessage, U7};
+
+    use super::*;
+
+    /// Program changes on channels 1 and 2, at the start of the sequence and just before a
+    /// second into it, so the synth counts how often each was played.
+    fn markers() -> Vec<MidiEvent> {
+        let marker = |channel, time| MidiEvent {
+            input: MidiMessage::ProgramChange(channel, U7::MIN),
+            time,
+        };
+        vec![
+            marker(Channel::Ch1, 0),
+            marker(Channel::Ch2, 999_000),
+            marker(Channel::Ch3, 1_000_000),
+        ]
+    }
+
+    /// Plays `seconds` of audio in odd sized buffers, so they don't line up with the events.
+    fn play_for(player: &mut Player, synth: &mut Synth, seconds: f32) {
+        let mut out = [0.0; 2 * 301];
+        let frames = (seconds * synth.sample_rate()) as usize;
+        for _ in 0..frames / 301 {
+            player.render(synth, &mut out, 2);
+        }
+    }
+
+    fn played(synth: &Synth) -> [u32; 3] {
+        [0, 1, 2].map(|c| synth.channels[c].program_changes())
+    }
+
+    #[test]
+    fn loops_without_losing_or_doubling_events() {
+        let mut synth = Synth::new(8000.0);
+        let mut player = Player::new();
+        player.load(markers());
+        player.apply(Transport::Loop {
+            enabled: true,
+            start: 0,
+            end: 1_000_000,
+        });
+        player.play();
+
+        // Four passes, the loop end itself is not part of the loop
+        play_for(&mut player, &mut synth, 3.5);
+        assert_eq!(played(&synth), [4, 3, 0]);
+
+        // Twice as fast, twice as many passes
+        player.apply(Transport::Speed(2.0));
+        play_for(&mut player, &mut synth, 2.0);
+        assert_eq!(played(&synth), [8, 7, 0]);
+    }
+
+    #[test]
+    fn seeks_past_events() {
+        let mut synth = Synth::new(8000.0);
+        let mut player = Player::new();
+        player.load(markers());
+        player.apply(Transport::Seek(500_000));
+        player.play();
+
+        play_for(&mut player, &mut synth, 1.0);
+        assert_eq!(played(&synth), [0, 1, 1]);
+        assert!(!player.status().playing);
+    }
+
+    #[test]
+    fn keeps_the_speed_in_range() {
+        let mut player = Player::new();
+        player.apply(Transport::Speed(0.0));
+        assert_eq!(player.speed, MIN_SPEED);
+        player.apply(Transport::Speed(f32::NAN));
+        assert_eq!(player.speed, MIN_SPEED);
+        player.apply(Transport::Speed(100.0));
+        assert_eq!(player.speed, MAX_SPEED);
+    }
+}
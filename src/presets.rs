@@ -0,0 +1,255 @@
+use std::f32::consts::PI;
+
+use crate::envelope::{Adsr, Curve};
+use crate::patch::{Partial, Patch};
+
+/// A built-in patch, generated from a formula rather than stored.
+pub struct Preset {
+    pub name: &'static str,
+    build: fn(&mut Patch),
+}
+
+impl Preset {
+    pub fn patch(&self) -> Patch {
+        let mut patch = Patch::new();
+        (self.build)(&mut patch);
+        patch
+    }
+}
+
+/// The factory bank, in program change order.
+pub const PRESETS: [Preset; 16] = [
+    Preset {
+        name: "Saw",
+        build: saw,
+    },
+    Preset {
+        name: "Square",
+        build: square,
+    },
+    Preset {
+        name: "Triangle",
+        build: triangle,
+    },
+    Preset {
+        name: "Pulse 25%",
+        build: |patch| pulse(patch, 0.25),
+    },
+    Preset {
+        name: "Pulse 12.5%",
+        build: |patch| pulse(patch, 0.125),
+    },
+    Preset {
+        name: "Organ 888000000",
+        build: |patch| organ(patch, [8, 8, 8, 0, 0, 0, 0, 0, 0]),
+    },
+    Preset {
+        name: "Organ 808000008",
+        build: |patch| organ(patch, [8, 0, 8, 0, 0, 0, 0, 0, 8]),
+    },
+    Preset {
+        name: "Organ 688600000",
+        build: |patch| organ(patch, [6, 8, 8, 6, 0, 0, 0, 0, 0]),
+    },
+    Preset {
+        name: "Organ 888888888",
+        build: |patch| organ(patch, [8; 9]),
+    },
+    Preset {
+        name: "Vowel A",
+        build: |patch| vowel(patch, [730.0, 1090.0, 2440.0]),
+    },
+    Preset {
+        name: "Vowel E",
+        build: |patch| vowel(patch, [530.0, 1840.0, 2480.0]),
+    },
+    Preset {
+        name: "Vowel I",
+        build: |patch| vowel(patch, [270.0, 2290.0, 3010.0]),
+    },
+    Preset {
+        name: "Vowel O",
+        build: |patch| vowel(patch, [570.0, 840.0, 2410.0]),
+    },
+    Preset {
+        name: "Vowel U",
+        build: |patch| vowel(patch, [300.0, 870.0, 2240.0]),
+    },
+    Preset {
+        name: "Church bell",
+        build: church_bell,
+    },
+    Preset {
+        name: "Glockenspiel",
+        build: glockenspiel,
+    },
+];
+
+/// Harmonic partials with the absolute amplitude `amplitude(n)` for partial number n.
+fn harmonic_spectrum(patch: &mut Patch, amplitude: impl Fn(f32) -> f32) {
+    let amplitudes = (1..=patch.partials.len())
+        .map(|n| amplitude(n as f32))
+        .collect::<Vec<_>>();
+    patch.set_amplitudes(&amplitudes);
+}
+
+/// Replaces the partials with `(ratio, absolute amplitude)` pairs, sorted by ratio.
+fn inharmonic_spectrum(patch: &mut Patch, partials: &[(f32, f32)]) {
+    let mut partials = partials.to_vec();
+    partials.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    patch.partials = partials
+        .iter()
+        .map(|&(ratio, _)| Partial {
+            amplitude: 0.0,
+            ratio,
+            detune: 0.0,
+            envelope: None,
+        })
+        .collect();
+    let amplitudes = partials.iter().map(|p| p.1).collect::<Vec<_>>();
+    patch.set_amplitudes(&amplitudes);
+
+    // Lanczos smoothing only makes sense for a long harmonic series
+    patch.smoothing = false;
+}
+
+fn saw(patch: &mut Patch) {
+    harmonic_spectrum(patch, |n| 1.0 / n);
+}
+
+fn square(patch: &mut Patch) {
+    harmonic_spectrum(patch, |n| if n % 2.0 == 1.0 { 1.0 / n } else { 0.0 });
+}
+
+fn triangle(patch: &mut Patch) {
+    harmonic_spectrum(patch, |n| if n % 2.0 == 1.0 { 1.0 / (n * n) } else { 0.0 });
+}
+
+/// A pulse that is high for `width` of the period, 0.5 being a square.
+fn pulse(patch: &mut Patch, width: f32) {
+    harmonic_spectrum(patch, |n| (PI * n * width).sin().abs() / n);
+}
+
+/// Drawbar levels from left to right, 16' to 1'.
+fn organ(patch: &mut Patch, levels: [u8; 9]) {
+    patch.set_drawbars(levels);
+    patch.click = 0.3;
+
+    patch.envelope = Adsr {
+        attack: 0.005,
+        decay: 0.0,
+        sustain: 1.0,
+        release: 0.05,
+        curve: Curve::Linear,
+    };
+}
+
+/// A saw-like glottal source through three formant resonances, in Hz. The formant positions
+/// are for a fundamental around C3, they move along with the key.
+fn vowel(patch: &mut Patch, formants: [f32; 3]) {
+    const FUNDAMENTAL: f32 = 130.8;
+    const BANDWIDTHS: [f32; 3] = [80.0, 100.0, 120.0];
+    const GAINS: [f32; 3] = [1.0, 0.5, 0.3];
+
+    harmonic_spectrum(patch, |n| {
+        let frequency = n * FUNDAMENTAL;
+        let resonance = formants
+            .iter()
+            .zip(BANDWIDTHS.iter().zip(GAINS))
+            .map(|(formant, (bandwidth, gain))| {
+                let x = (frequency - formant) / (bandwidth / 2.0);
+                gain / (1.0 + x * x)
+            })
+            .sum::<f32>();
+        resonance / n
+    });
+
+    patch.envelope = Adsr {
+        attack: 0.08,
+        decay: 0.2,
+        sustain: 0.8,
+        release: 0.3,
+        curve: Curve::Linear,
+    };
+}
+
+/// Struck partials that die away on their own, the higher ones faster.
+fn decaying(patch: &mut Patch, longest: f32) {
+    patch.envelope = Adsr {
+        attack: 0.002,
+        decay: longest,
+        sustain: 0.0,
+        release: longest,
+        curve: Curve::Exponential,
+    };
+    for partial in &mut patch.partials {
+        let time = longest / partial.ratio.max(1.0).sqrt();
+        partial.envelope = Some(Adsr {
+            decay: time,
+            release: time,
+            ..patch.envelope
+        });
+    }
+}
+
+/// Partials of a tuned church bell, named hum, prime, tierce, quint, nominal and so on.
+fn church_bell(patch: &mut Patch) {
+    inharmonic_spectrum(
+        patch,
+        &[
+            (0.5, 0.6),
+            (1.0, 0.8),
+            (1.183, 0.7),
+            (1.506, 0.4),
+            (2.0, 1.0),
+            (2.514, 0.4),
+            (2.662, 0.3),
+            (3.011, 0.35),
+            (4.166, 0.25),
+            (5.433, 0.18),
+            (6.796, 0.12),
+            (8.215, 0.08),
+        ],
+    );
+    decaying(patch, 8.0);
+}
+
+/// Modes of a bar free at both ends.
+fn glockenspiel(patch: &mut Patch) {
+    inharmonic_spectrum(
+        patch,
+        &[
+            (1.0, 1.0),
+            (2.756, 0.5),
+            (5.404, 0.25),
+            (8.933, 0.12),
+            (13.344, 0.06),
+        ],
+    );
+    decaying(patch, 3.0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::patch::MAX_PARTIALS;
+
+    #[test]
+    fn presets_fit_the_editor() {
+        for preset in &PRESETS {
+            let patch = preset.patch();
+            assert!(!patch.partials.is_empty(), "{}", preset.name);
+            assert!(patch.partials.len() <= MAX_PARTIALS, "{}", preset.name);
+            for partial in &patch.partials {
+                assert!((0.0..=1.0).contains(&partial.amplitude), "{}", preset.name);
+            }
+            assert!(
+                patch.partials.iter().any(|p| p.amplitude > 0.0),
+                "{}",
+                preset.name
+            );
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::sync::mpsc;
+use std::thread;
+
+use wmidi::MidiMessage;
+
+use crate::midi::{MidiEvent, Track};
+use crate::patch::{Patch, MAX_PARTIALS};
+use crate::player::{self, Player, PlayerStatus, Transport};
+use crate::ringbuffer::{ring_buffer, Consumer, Producer};
+use crate::synth::{Controllers, Synth, CHANNELS};
+
+/// Room for a burst of MIDI plus a patch edit every UI frame.
+const COMMAND_CAPACITY: usize = 1024;
+
+/// About a third of a second at 48 kHz, more than the UI drains between two frames.
+const SCOPE_CAPACITY: usize = 4096 * 4;
+
+const STATUS_CAPACITY: usize = 16;
+
+/// Room for a burst of live MIDI, e.g. a chord with a sweep of controllers.
+const LIVE_CAPACITY: usize = 1024;
+
+/// Everything the audio thread can be asked to do.
+pub enum Command {
+    /// Replaces the patch of a channel, 0 to 15.
+    SetPatch(usize, Box<Patch>),
+    Load(Vec<MidiEvent>),
+    Transport(Transport),
+}
+
+#[derive(Clone, Copy)]
+pub struct ChannelStatus {
+    pub controllers: Controllers,
+    pub poly_pressure: f32,
+    pub active_voices: usize,
+    pub program_changes: u32,
+}
+
+/// What the UI needs to know about the audio thread, sent once per buffer.
+#[derive(Clone, Copy)]
+pub struct Status {
+    pub channels: [ChannelStatus; CHANNELS],
+    pub player: PlayerStatus,
+}
+
+/// Owned by the audio callback. Takes commands from a `Remote` and reports back to it, all
+/// through lock-free queues.
+pub struct Processor {
+    pub synth: Synth,
+    player: Player,
+    commands: Consumer<Command>,
+    live: Consumer<MidiMessage<'static>>,
+    /// Commands holding memory go back to the UI thread to be freed there.
+    garbage: Producer<Command>,
+    status: Producer<Status>,
+    scope: Producer<f32>,
+}
+
+impl Processor {
+    /// Applies pending commands and live MIDI, call once at the start of every buffer. Live
+    /// MIDI takes effect at the start of the buffer it arrives in, so it is only as precise as
+    /// the buffer size; the player and the offline renderer place their events exactly.
+    pub fn receive(&mut self) {
+        while let Some(message) = self.live.pop() {
+            self.synth.handle_midi(&message);
+        }
+
+        while let Some(command) = self.commands.peek() {
+            // What a command replaces has to go back to be freed, wait for room for it rather
+            // than dropping it here
+            let frees = matches!(command, Command::SetPatch(..) | Command::Load(_));
+            if frees && self.garbage.is_full() {
+                break;
+            }
+
+            match self.commands.pop() {
+                Some(Command::SetPatch(channel, mut patch)) => {
+                    std::mem::swap(&mut self.synth.channels[channel].patch, &mut patch);
+                    self.discard(Command::SetPatch(channel, patch));
+                }
+                Some(Command::Load(events)) => {
+                    let previous = self.player.load(events);
+                    self.discard(Command::Load(previous));
+                }
+                Some(Command::Transport(transport)) => self.player.apply(transport),
+                None => break,
+            }
+        }
+    }
+
+    /// Sends a spent command back to the UI thread. There is always room, `receive` checks
+    /// first, but should that ever fail it is leaked rather than freed on the audio thread.
+    fn discard(&mut self, command: Command) {
+        if let Err(command) = self.garbage.push(command) {
+            std::mem::forget(command);
+        }
+    }
+
+    /// Renders interleaved frames of `channels` samples.
+    pub fn render(&mut self, out: &mut [f32], channels: usize) {
+        self.player.render(&mut self.synth, out, channels);
+
+        // The scope misses samples if the UI falls behind, which is fine
+        for frame in out.chunks(channels) {
+            let mono = match frame {
+                [left, right, ..] => (left + right) / 2.0,
+                [mono] => *mono,
+                [] => 0.0,
+            };
+            let _ = self.scope.push(mono);
+        }
+    }
+
+    /// Reports the current state, call once at the end of every buffer.
+    pub fn report(&mut self) {
+        let _ = self.status.push(self.status());
+    }
+
+    fn status(&self) -> Status {
+        let mut channels = [ChannelStatus {
+            controllers: Controllers::new(),
+            poly_pressure: 0.0,
+            active_voices: 0,
+            program_changes: 0,
+        }; CHANNELS];
+        for (status, channel) in channels.iter_mut().zip(&self.synth.channels) {
+            *status = ChannelStatus {
+                controllers: channel.controllers,
+                poly_pressure: channel.poly_pressure(),
+                active_voices: channel.active_voices(),
+                program_changes: channel.program_changes(),
+            };
+        }
+
+        Status {
+            channels,
+            player: self.player.status(),
+        }
+    }
+}
+
+/// The UI side of a `Processor`.
+pub struct Remote {
+    commands: Producer<Command>,
+    garbage: Consumer<Command>,
+    statuses: Consumer<Status>,
+    scope: Consumer<f32>,
+    /// Latest state reported by the audio thread.
+    pub status: Status,
+}
+
+impl Remote {
+    pub fn send(&mut self, command: Command) {
+        if self.commands.push(command).is_err() {
+            eprintln!("audio thread is not keeping up, dropped a command");
+        }
+    }
+
+    pub fn set_patch(&mut self, channel: usize, patch: &Patch) {
+        let mut patch = Box::new(patch.clone());
+        // Room for any preset a program change might copy in
+        patch
+            .partials
+            .reserve(MAX_PARTIALS.saturating_sub(patch.partials.len()));
+        self.send(Command::SetPatch(channel, patch));
+    }
+
+    pub fn load(&mut self, tracks: &[Track]) {
+        self.send(Command::Load(player::merge(tracks)));
+    }
+
+    /// Frees spent commands and picks up the latest status, call once per UI frame.
+    pub fn update(&mut self) {
+        while self.garbage.pop().is_some() {}
+
+        while let Some(status) = self.statuses.pop() {
+            self.status = status;
+        }
+    }
+
+    /// Samples played since the last call, oldest first.
+    pub fn scope_samples(&mut self) -> impl Iterator<Item = f32> + '_ {
+        std::iter::from_fn(move || self.scope.pop())
+    }
+}
+
+/// Forwards live MIDI to the audio thread on a thread of its own, so it doesn't wait for the
+/// next UI frame.
+pub struct LiveInput {
+    queue: Producer<MidiMessage<'static>>,
+}
+
+impl LiveInput {
+    pub fn spawn(mut self, messages: mpsc::Receiver<MidiMessage<'static>>) {
+        thread::spawn(move || {
+            for message in messages {
+                if self.queue.push(message).is_err() {
+                    eprintln!("audio thread is not keeping up, dropped live MIDI");
+                }
+            }
+        });
+    }
+}
+
+/// Splits a synth into the part that is moved into the audio callback, the part the UI keeps
+/// to control it and the entry for live MIDI.
+pub fn split(synth: Synth, player: Player) -> (Remote, Processor, LiveInput) {
+    let (command_tx, command_rx) = ring_buffer(COMMAND_CAPACITY);
+    let (live_tx, live_rx) = ring_buffer(LIVE_CAPACITY);
+    let (garbage_tx, garbage_rx) = ring_buffer(COMMAND_CAPACITY);
+    let (status_tx, status_rx) = ring_buffer(STATUS_CAPACITY);
+    let (scope_tx, scope_rx) = ring_buffer(SCOPE_CAPACITY);
+
+    let processor = Processor {
+        synth,
+        player,
+        commands: command_rx,
+        live: live_rx,
+        garbage: garbage_tx,
+        status: status_tx,
+        scope: scope_tx,
+    };
+
+    let remote = Remote {
+        commands: command_tx,
+        garbage: garbage_rx,
+        statuses: status_rx,
+        scope: scope_rx,
+        status: processor.status(),
+    };
+
+    (remote, processor, LiveInput { queue: live_tx })
+}
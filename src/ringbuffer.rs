@@ -1,24 +1,152 @@
-use std::collections::VecDeque;
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-pub struct RingBuffer<T> {
-    data: VecDeque<T>,
+/// Fixed size queue between exactly one producer and one consumer thread. Neither side ever
+/// blocks or allocates, so both ends can be used from the audio callback.
+pub fn ring_buffer<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let shared = Arc::new(Shared {
+        slots: (0..capacity)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+
+    (
+        Producer {
+            shared: shared.clone(),
+        },
+        Consumer { shared },
+    )
+}
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Count of values popped so far, only written by the consumer.
+    head: AtomicUsize,
+    /// Count of values pushed so far, only written by the producer.
+    tail: AtomicUsize,
 }
 
-impl<T> RingBuffer<T> {
-    pub fn with_size(size: usize) -> Self {
-        Self {
-            data: VecDeque::with_capacity(size),
+// Slots between head and tail belong to the consumer, all others to the producer.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.slots[index % self.slots.len()].get()
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            unsafe { (*self.slot(head)).as_mut_ptr().drop_in_place() };
+            head = head.wrapping_add(1);
         }
     }
+}
+
+pub struct Producer<T> {
+    shared: Arc<Shared<T>>,
+}
 
-    pub fn push(&mut self, val: T) {
-        if self.data.len() + 1 >= self.data.capacity() {
-            self.data.pop_back();
+impl<T> Producer<T> {
+    /// Hands the value back when the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let head = self.shared.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == self.shared.slots.len() {
+            return Err(value);
         }
-        self.data.push_front(val);
+
+        unsafe { (*self.shared.slot(tail)).as_mut_ptr().write(value) };
+        self.shared
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Whether the next `push` would be rejected.
+    pub fn is_full(&self) -> bool {
+        let tail = self.shared.tail.load(Ordering::Relaxed);
+        let head = self.shared.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head) == self.shared.slots.len()
     }
+}
+
+pub struct Consumer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Consumer<T> {
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.shared.head.load(Ordering::Relaxed);
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        let value = unsafe { (*self.shared.slot(head)).as_ptr().read() };
+        self.shared
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+
+        Some(value)
+    }
+
+    /// The value `pop` would return next, left in the queue.
+    pub fn peek(&self) -> Option<&T> {
+        let head = self.shared.head.load(Ordering::Relaxed);
+        let tail = self.shared.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+
+        // The slot stays with the consumer until head moves past it, which takes `&mut self`
+        Some(unsafe { &*(*self.shared.slot(head)).as_ptr() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_order_and_rejects_when_full() {
+        let (mut producer, mut consumer) = ring_buffer(3);
+
+        for round in 0..10 {
+            assert!(producer.push(round).is_ok());
+            assert!(producer.push(round + 1).is_ok());
+            assert!(producer.push(round + 2).is_ok());
+            assert!(producer.is_full());
+            assert_eq!(producer.push(round + 3), Err(round + 3));
+
+            assert_eq!(consumer.peek(), Some(&round));
+            assert_eq!(consumer.pop(), Some(round));
+            assert!(!producer.is_full());
+            assert_eq!(consumer.pop(), Some(round + 1));
+            assert_eq!(consumer.pop(), Some(round + 2));
+            assert_eq!(consumer.pop(), None);
+        }
+    }
+
+    #[test]
+    fn drops_values_left_in_the_queue() {
+        let value = Arc::new(());
+
+        let (mut producer, consumer) = ring_buffer(4);
+        producer.push(value.clone()).unwrap();
+        producer.push(value.clone()).unwrap();
+        assert_eq!(Arc::strong_count(&value), 3);
 
-    pub fn get(&self, idx: usize) -> Option<&T> {
-        self.data.get(idx)
+        drop(producer);
+        drop(consumer);
+        assert_eq!(Arc::strong_count(&value), 1);
     }
 }
@@ -0,0 +1,575 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use wmidi::MidiMessage;
+
+use crate::midi::{self, MidiEvent};
+
+/// Default tempo of a Standard MIDI File until the first tempo event, 120 bpm.
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// Largest variable-length quantity, four groups of seven bits.
+const MAX_VLQ: u32 = 0x0fff_ffff;
+
+pub enum Division {
+    /// Ticks per quarter note.
+    Metrical(u16),
+    Timecode {
+        frames_per_second: u8,
+        ticks_per_frame: u8,
+    },
+}
+
+pub enum EventKind {
+    Midi(MidiMessage<'static>),
+    /// Microseconds per quarter note.
+    Tempo(u32),
+}
+
+pub struct TrackEvent {
+    pub tick: u64,
+    pub kind: EventKind,
+}
+
+pub struct Track {
+    pub name: Option<String>,
+    pub events: Vec<TrackEvent>,
+}
+
+pub struct Smf {
+    pub division: Division,
+    pub tracks: Vec<Track>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], anyhow::Error> {
+        if self.data.len() - self.pos < len {
+            bail!("unexpected end of file");
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, anyhow::Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn peek(&self) -> Result<u8, anyhow::Error> {
+        self.data
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("unexpected end of file"))
+    }
+
+    fn u16(&mut self) -> Result<u16, anyhow::Error> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, anyhow::Error> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Variable-length quantity, at most four bytes.
+    fn vlq(&mut self) -> Result<u32, anyhow::Error> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            value = (value << 7) | (b & 0x7f) as u32;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("variable-length quantity is too long")
+    }
+}
+
+impl Smf {
+    pub fn read(path: &Path) -> Result<Smf, anyhow::Error> {
+        let data = std::fs::read(path)?;
+        Smf::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Smf, anyhow::Error> {
+        let mut reader = Reader::new(data);
+
+        if reader.bytes(4)? != b"MThd" {
+            bail!("not a Standard MIDI File");
+        }
+        let header_len = reader.u32()? as usize;
+        let mut header = Reader::new(reader.bytes(header_len)?);
+        let format = header.u16()?;
+        if format > 2 {
+            bail!("unsupported MIDI file format {}", format);
+        }
+        let track_count = header.u16()?;
+        let division = header.u16()?;
+
+        let division = if division & 0x8000 == 0 {
+            Division::Metrical(division)
+        } else {
+            Division::Timecode {
+                frames_per_second: (-((division >> 8) as i8)) as u8,
+                ticks_per_frame: (division & 0xff) as u8,
+            }
+        };
+
+        let mut tracks = vec![];
+
+        while !reader.is_empty() && tracks.len() < track_count as usize {
+            let id = reader.bytes(4)?;
+            let len = reader.u32()? as usize;
+            let chunk = reader.bytes(len)?;
+
+            // Unknown chunks are to be skipped, as per the spec
+            if id == b"MTrk" {
+                tracks.push(parse_track(chunk)?);
+            }
+        }
+
+        Ok(Smf { division, tracks })
+    }
+
+    /// Builds a format 1 file from tracks on the microsecond timeline, at a constant tempo. The
+    /// first track only holds the tempo, as is customary for format 1.
+    pub fn from_timeline(tracks: &[midi::Track], ticks_per_quarter: u16, tempo: u32) -> Smf {
+        let start = tracks
+            .iter()
+            .filter_map(|t| t.events.first())
+            .map(|e| e.time)
+            .min()
+            .unwrap_or(0);
+
+        let mut smf_tracks = vec![Track {
+            name: None,
+            events: vec![TrackEvent {
+                tick: 0,
+                kind: EventKind::Tempo(tempo),
+            }],
+        }];
+
+        for track in tracks {
+            smf_tracks.push(Track {
+                name: Some(track.name.clone()),
+                events: track
+                    .events
+                    .iter()
+                    .filter(|e| e.input.channel().is_some())
+                    .map(|e| TrackEvent {
+                        tick: (e.time - start) * ticks_per_quarter as u64 / tempo as u64,
+                        kind: EventKind::Midi(e.input.clone()),
+                    })
+                    .collect(),
+            });
+        }
+
+        Smf {
+            division: Division::Metrical(ticks_per_quarter),
+            tracks: smf_tracks,
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        let division = match self.division {
+            Division::Metrical(ticks) => ticks,
+            Division::Timecode {
+                frames_per_second,
+                ticks_per_frame,
+            } => ((-(frames_per_second as i8) as u8 as u16) << 8) | ticks_per_frame as u16,
+        };
+
+        out.write_all(b"MThd")?;
+        out.write_all(&6u32.to_be_bytes())?;
+        out.write_all(&1u16.to_be_bytes())?;
+        out.write_all(&(self.tracks.len() as u16).to_be_bytes())?;
+        out.write_all(&division.to_be_bytes())?;
+
+        for track in &self.tracks {
+            let data = encode_track(track)?;
+            out.write_all(b"MTrk")?;
+            out.write_all(&(data.len() as u32).to_be_bytes())?;
+            out.write_all(&data)?;
+        }
+
+        out.flush()?;
+
+        Ok(())
+    }
+
+    /// Converts every track into events on the microsecond timeline used by `MidiEvent::time`,
+    /// following the tempo map of the whole file.
+    pub fn timeline(&self) -> Vec<midi::Track> {
+        let tempo_map = TempoMap::new(self);
+
+        self.tracks
+            .iter()
+            .enumerate()
+            .map(|(i, track)| midi::Track {
+                name: track
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("Track {}", i + 1)),
+                events: track
+                    .events
+                    .iter()
+                    .filter_map(|e| match &e.kind {
+                        EventKind::Midi(message) => Some(MidiEvent {
+                            input: message.clone(),
+                            time: tempo_map.micros(e.tick),
+                        }),
+                        EventKind::Tempo(_) => None,
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// All tracks merged into a single timeline, ordered by time.
+    pub fn merged_timeline(&self) -> Vec<MidiEvent> {
+        let mut events = self
+            .timeline()
+            .into_iter()
+            .flat_map(|t| t.events)
+            .collect::<Vec<_>>();
+        events.sort_by_key(|e| e.time);
+        events
+    }
+}
+
+fn parse_track(data: &[u8]) -> Result<Track, anyhow::Error> {
+    let mut reader = Reader::new(data);
+
+    let mut tick = 0;
+    let mut running_status = None;
+
+    let mut track = Track {
+        name: None,
+        events: vec![],
+    };
+
+    while !reader.is_empty() {
+        tick += reader.vlq()? as u64;
+
+        let status = if reader.peek()? & 0x80 != 0 {
+            reader.u8()?
+        } else {
+            running_status.ok_or_else(|| anyhow!("data byte without running status"))?
+        };
+
+        // Meta and system exclusive events cancel running status
+        if status >= 0xf0 {
+            running_status = None;
+        }
+
+        match status {
+            0xff => {
+                let kind = reader.u8()?;
+                let len = reader.vlq()? as usize;
+                let data = reader.bytes(len)?;
+                match kind {
+                    0x03 if track.name.is_none() => {
+                        track.name = Some(String::from_utf8_lossy(data).into_owned());
+                    }
+                    0x2f => break,
+                    0x51 if len == 3 => track.events.push(TrackEvent {
+                        tick,
+                        kind: EventKind::Tempo(u32::from_be_bytes([0, data[0], data[1], data[2]])),
+                    }),
+                    _ => {}
+                }
+            }
+            0xf0 | 0xf7 => {
+                let len = reader.vlq()? as usize;
+                reader.bytes(len)?;
+            }
+            0x80..=0xef => {
+                running_status = Some(status);
+
+                let len = match status & 0xf0 {
+                    0xc0 | 0xd0 => 1,
+                    _ => 2,
+                };
+                let mut bytes = [status, 0, 0];
+                bytes[1..=len].copy_from_slice(reader.bytes(len)?);
+
+                if let Ok(message) = MidiMessage::try_from(&bytes[..=len]) {
+                    track.events.push(TrackEvent {
+                        tick,
+                        kind: EventKind::Midi(message.to_owned()),
+                    });
+                }
+            }
+            _ => bail!("unexpected status byte {:#x}", status),
+        }
+    }
+
+    Ok(track)
+}
+
+fn write_vlq(out: &mut Vec<u8>, mut value: u32) -> Result<(), anyhow::Error> {
+    if value > MAX_VLQ {
+        bail!("{} is too large for a variable-length quantity", value);
+    }
+
+    let mut bytes = [0; 4];
+    let mut len = 0;
+    loop {
+        bytes[len] = (value & 0x7f) as u8;
+        len += 1;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+    }
+    for i in (0..len).rev() {
+        out.push(if i > 0 { bytes[i] | 0x80 } else { bytes[i] });
+    }
+    Ok(())
+}
+
+fn encode_track(track: &Track) -> Result<Vec<u8>, anyhow::Error> {
+    let mut data = vec![];
+
+    if let Some(name) = &track.name {
+        write_vlq(&mut data, 0)?;
+        data.extend_from_slice(&[0xff, 0x03]);
+        write_vlq(&mut data, name.len() as u32)?;
+        data.extend_from_slice(name.as_bytes());
+    }
+
+    let mut events = track.events.iter().collect::<Vec<_>>();
+    events.sort_by_key(|e| e.tick);
+
+    let mut tick = 0;
+    let mut buffer = [0; 3];
+
+    for event in events {
+        let delta = event.tick - tick;
+        if delta > MAX_VLQ as u64 {
+            bail!(
+                "{} ticks between two events, too long for a MIDI file",
+                delta
+            );
+        }
+        write_vlq(&mut data, delta as u32)?;
+        tick = event.tick;
+
+        match &event.kind {
+            EventKind::Midi(message) => {
+                let len = message.copy_to_slice(&mut buffer)?;
+                data.extend_from_slice(&buffer[..len]);
+            }
+            EventKind::Tempo(tempo) => {
+                data.extend_from_slice(&[0xff, 0x51, 0x03]);
+                data.extend_from_slice(&tempo.to_be_bytes()[1..]);
+            }
+        }
+    }
+
+    write_vlq(&mut data, 0)?;
+    data.extend_from_slice(&[0xff, 0x2f, 0x00]);
+
+    Ok(data)
+}
+
+/// Maps ticks to microseconds from the start of the file.
+struct TempoMap {
+    /// Tick, time in microseconds at that tick, and tempo from that tick on.
+    changes: Vec<(u64, u64, u32)>,
+    ticks_per_quarter: u64,
+    /// Only set for timecode-based files, where the tempo is irrelevant.
+    micros_per_tick: Option<f64>,
+}
+
+impl TempoMap {
+    fn new(smf: &Smf) -> Self {
+        let (ticks_per_quarter, micros_per_tick) = match smf.division {
+            Division::Metrical(ticks) => (ticks.max(1) as u64, None),
+            Division::Timecode {
+                frames_per_second,
+                ticks_per_frame,
+            } => {
+                // 29 is drop-frame 30 fps
+                let fps = if frames_per_second == 29 {
+                    29.97
+                } else {
+                    frames_per_second as f64
+                };
+                (1, Some(1_000_000.0 / (fps * ticks_per_frame.max(1) as f64)))
+            }
+        };
+
+        let mut tempos = smf
+            .tracks
+            .iter()
+            .flat_map(|t| t.events.iter())
+            .filter_map(|e| match e.kind {
+                EventKind::Tempo(tempo) => Some((e.tick, tempo)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        tempos.sort_by_key(|&(tick, _)| tick);
+
+        let mut changes = vec![(0, 0, DEFAULT_TEMPO)];
+        for (tick, tempo) in tempos {
+            let &(last_tick, last_micros, last_tempo) = changes.last().unwrap();
+            let micros = last_micros + (tick - last_tick) * last_tempo as u64 / ticks_per_quarter;
+            if tick == last_tick {
+                changes.pop();
+            }
+            changes.push((tick, micros, tempo));
+        }
+
+        TempoMap {
+            changes,
+            ticks_per_quarter,
+            micros_per_tick,
+        }
+    }
+
+    fn micros(&self, tick: u64) -> u64 {
+        if let Some(micros_per_tick) = self.micros_per_tick {
+            return (tick as f64 * micros_per_tick) as u64;
+        }
+
+        let idx = match self.changes.binary_search_by_key(&tick, |&(t, _, _)| t) {
+            Ok(idx) => idx,
+            Err(idx) => idx - 1,
+        };
+        let (change_tick, micros, tempo) = self.changes[idx];
+        micros + (tick - change_tick) * tempo as u64 / self.ticks_per_quarter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meta_events_cancel_running_status() {
+        // Note on, a text event, then a data byte that would need the note on's status
+        let data = [
+            0x00, 0x90, 0x3c, 0x40, 0x00, 0xff, 0x01, 0x00, 0x00, 0x3c, 0x00,
+        ];
+        assert!(parse_track(&data).is_err());
+
+        // With the status repeated it is fine
+        let data = [
+            0x00, 0x90, 0x3c, 0x40, 0x00, 0xff, 0x01, 0x00, 0x00, 0x90, 0x3c, 0x00,
+        ];
+        assert_eq!(parse_track(&data).unwrap().events.len(), 2);
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let event = |time, message| MidiEvent {
+            input: message,
+            time,
+        };
+        let (channel, note) = (wmidi::Channel::Ch2, wmidi::Note::C4);
+        let mut take = vec![midi::Track {
+            name: String::from("take"),
+            events: vec![
+                event(
+                    1_000_000,
+                    MidiMessage::NoteOn(channel, note, wmidi::U7::MAX),
+                ),
+                event(
+                    1_500_000,
+                    MidiMessage::NoteOff(channel, note, wmidi::U7::MIN),
+                ),
+            ],
+        }];
+
+        let path = std::env::temp_dir().join(format!("music-box-smf-{}.mid", std::process::id()));
+        Smf::from_timeline(&take, 480, 500_000)
+            .write(&path)
+            .unwrap();
+        let read = Smf::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        // A tempo track comes first, and times start at the first event
+        let tracks = read.unwrap().timeline();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[1].name, "take");
+        let events = &tracks[1].events;
+        assert_eq!(events.len(), 2);
+        assert_eq!((events[0].time, events[1].time), (0, 500_000));
+        assert_eq!(events[1].input, take[0].events[1].input);
+
+        let mut data = vec![];
+        assert!(write_vlq(&mut data, MAX_VLQ).is_ok());
+        assert_eq!(data, [0xff, 0xff, 0xff, 0x7f]);
+
+        // Further apart than a variable-length quantity can say
+        take[0].events[1].time = 1_000_000_000_000;
+        assert!(Smf::from_timeline(&take, 480, 500_000)
+            .write(&path)
+            .is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn follows_tempo_changes() {
+        let tempo = |tick, tempo| TrackEvent {
+            tick,
+            kind: EventKind::Tempo(tempo),
+        };
+        let smf = Smf {
+            division: Division::Metrical(480),
+            tracks: vec![Track {
+                name: None,
+                // Twice as fast after two quarter notes at the default 120 bpm
+                events: vec![tempo(960, 250_000)],
+            }],
+        };
+
+        let tempo_map = TempoMap::new(&smf);
+        assert_eq!(tempo_map.micros(480), 500_000);
+        assert_eq!(tempo_map.micros(960), 1_000_000);
+        assert_eq!(tempo_map.micros(1440), 1_250_000);
+    }
+
+    #[test]
+    fn reads_timecode_division() {
+        #[rustfmt::skip]
+        let data = [
+            b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 0, 0, 1,
+            // 25 fps, 40 ticks per frame, so a tick is a millisecond
+            0xe7, 0x28,
+            b'M', b'T', b'r', b'k', 0, 0, 0, 9,
+            // A note on after 1000 ticks, then the end of the track
+            0x87, 0x68, 0x90, 0x3c, 0x40, 0x00, 0xff, 0x2f, 0x00,
+        ];
+
+        let smf = Smf::parse(&data).unwrap();
+        assert!(matches!(
+            smf.division,
+            Division::Timecode {
+                frames_per_second: 25,
+                ticks_per_frame: 40
+            }
+        ));
+        assert_eq!(smf.timeline()[0].events[0].time, 1_000_000);
+    }
+}
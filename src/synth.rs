@@ -1,155 +1,1232 @@
 use std::f32::consts::PI;
 
-use wmidi::Note;
+use wmidi::{ControlFunction, MidiMessage, Note, U14};
 
-use crate::ringbuffer::RingBuffer;
+use crate::oscillator::{self, Bank};
+use crate::patch::{
+    MorphSource, Patch, Priority, Stealing, VoiceMode, DRAWBAR_SCALE, MAX_PARTIALS, MAX_POLYPHONY,
+};
+use crate::presets::PRESETS;
+
+/// Number of MIDI channels, each with its own patch.
+pub const CHANNELS: usize = 16;
+
+/// Frames rendered at a time. Pitch and partial gains are worked out once per block, so events
+/// only land in between blocks when the caller splits its buffer at them. The player and the
+/// offline renderer do; live MIDI is applied at the start of an audio buffer.
+const BLOCK: usize = 64;
+
+/// Partials fade out between this fraction of the Nyquist frequency and Nyquist itself, so
+/// they don't pop when a bend moves them across. Above Nyquist they would alias.
+const NYQUIST_FADE: f64 = 0.9;
+
+/// How long a stolen voice takes to fade out, in seconds. Short, but long enough not to click.
+const STEAL_FADE: f32 = 0.005;
+
+/// Length of the key click at the start of a note, in seconds.
+const CLICK_TIME: f32 = 0.004;
+
+/// Peak of the key click at full level, relative to a full drawbar.
+const CLICK_GAIN: f32 = 2.0;
+
+/// A pitch change in progress, towards the voice's key.
+#[derive(Clone, Copy)]
+struct Glide {
+    /// In MIDI note numbers.
+    from: f64,
+    /// Sample at which the glide started.
+    start: u64,
+}
 
 struct Voice {
     key: Note,
+    /// Portamento towards `key`, if any.
+    glide: Option<Glide>,
     velocity: f32,
-    time: f32,
-    release_time: Option<f32>,
+    /// Sample at which the note started, and at which it was released.
+    time: u64,
+    release_time: Option<u64>,
+    /// Phase of every partial in cycles, wrapped to 0.0..1.0. Kept in f64 so long notes don't
+    /// drift out of tune. Room for the most partials any patch has, so neither a new note nor a
+    /// patch with more partials allocates on the audio thread.
+    phases: [f64; MAX_PARTIALS],
+    /// Polyphonic aftertouch, 0.0 to 1.0.
+    pressure: f32,
+    /// The key is physically held down.
+    key_down: bool,
+    /// Key was let go while the sustain pedal was down.
+    sustained: bool,
+    /// Key was down when the sostenuto pedal was pressed.
+    sostenuto: bool,
+    /// Sample at which the voice was taken for a new note, it fades out quickly from there.
+    stolen: Option<u64>,
+    /// The patch percussion sounds on this voice, it started with no other key held.
+    percussion: bool,
+    percussion_phase: f64,
+    /// How far the spectrum had morphed at the end of the last block.
+    morph: Option<f32>,
 }
 
-pub struct Synth {
-    pub sample_clock: f32,
-    pub sample_rate: f32,
-    time: f32,
-    keys_pressed: Vec<Voice>,
-    sample_buffer: RingBuffer<f32>,
-    samples: u64,
-    pub partials: Vec<f32>,
+impl Voice {
+    fn is_held(&self) -> bool {
+        self.key_down || self.sustained || self.sostenuto
+    }
+
+    /// Pitch in MIDI note numbers at sample `now`, for a glide taking `glide` samples.
+    fn pitch(&self, now: u64, glide: f64) -> f64 {
+        let key = u8::from(self.key) as f64;
+        match self.glide {
+            Some(g) if glide > 0.0 => {
+                let progress = ((now - g.start) as f64 / glide).min(1.0);
+                g.from + (key - g.from) * progress
+            }
+            _ => key,
+        }
+    }
 }
 
-struct ADSR {
-    attack: f32,
-    decay: f32,
-    sustain: f32,
-    release: f32,
+/// Frequency in Hz of a pitch in MIDI note numbers.
+fn frequency(pitch: f64) -> f64 {
+    440.0 * 2.0f64.powf((pitch - 69.0) / 12.0)
 }
 
-fn lin_lerp(a: f32, b: f32, mut t: f32) -> f32 {
-    if t < 0.0 {
-        t = 0.0;
-    }
-    if t > 1.0 {
-        t = 1.0;
-    }
-    a * (1.0 - t) + b * t
+/// Equal power gains for the left and right channel, `pan` is clamped to -1.0..1.0.
+fn pan_gains(pan: f32) -> [f32; 2] {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * PI / 4.0;
+    [angle.cos(), angle.sin()]
 }
 
-impl ADSR {
-    pub fn evaluate(&self, t: f32, t2: f32, down: bool) -> f32 {
-        let v = if t < self.attack {
-            lin_lerp(0.0, 1.0, t / self.attack)
-        } else if t < self.attack + self.decay {
-            lin_lerp(1.0, self.sustain, t / (self.attack + self.decay))
-        } else {
-            self.sustain
-        };
-        if down {
-            v
-        } else {
-            lin_lerp(v, 0.0, t2 / self.release)
+/// Channel controller state, as last received over MIDI.
+#[derive(Clone, Copy)]
+pub struct Controllers {
+    /// -1.0 to 1.0
+    pub pitch_bend: f32,
+    /// In semitones.
+    pub pitch_bend_range: f32,
+    pub mod_wheel: f32,
+    /// Channel aftertouch, 0.0 to 1.0.
+    pub pressure: f32,
+    pub sustain: bool,
+    pub sostenuto: bool,
+    pub program: u8,
+}
+
+impl Controllers {
+    pub fn new() -> Self {
+        Controllers {
+            pitch_bend: 0.0,
+            pitch_bend_range: 2.0,
+            mod_wheel: 0.0,
+            pressure: 0.0,
+            sustain: false,
+            sostenuto: false,
+            program: 0,
         }
     }
+
+    pub fn reset(&mut self) {
+        // Reset All Controllers leaves the bend range and program alone, as per RP-015
+        self.pitch_bend = 0.0;
+        self.mod_wheel = 0.0;
+        self.pressure = 0.0;
+        self.sustain = false;
+        self.sostenuto = false;
+    }
 }
 
-impl Synth {
+/// One MIDI channel with its own patch, controllers and voices.
+pub struct Channel {
+    sample_rate: f32,
+    keys_pressed: Vec<Voice>,
+    /// Keys physically held down in the order they were pressed, for picking the note a
+    /// monophonic patch plays.
+    held_keys: Vec<(Note, f32)>,
+    pub controllers: Controllers,
+    /// Counts program changes, so the UI can tell when the patch was replaced by a preset.
+    program_changes: u32,
+    /// State of the noise generator for key clicks.
+    noise: u32,
+    samples: u64,
+    pub patch: Patch,
+}
+
+impl Channel {
     pub fn new(sample_rate: f32) -> Self {
-        Synth {
-            sample_clock: 0.0,
+        Channel {
             sample_rate,
-            time: 0.0,
-            keys_pressed: vec![],
-            sample_buffer: RingBuffer::with_size(4096 * 4),
+            // Reserved up front so note-ons don't allocate on the audio thread, with room for
+            // stolen voices that are still fading out
+            keys_pressed: Vec::with_capacity(MAX_POLYPHONY * 2),
+            held_keys: Vec::with_capacity(128),
+            controllers: Controllers::new(),
+            program_changes: 0,
+            noise: 0x9e37_79b9,
             samples: 0,
-            partials: vec![1.0; 64],
+            patch: Patch::new(),
         }
     }
 
-    pub fn sample_buffer(&self) -> &RingBuffer<f32> {
-        &self.sample_buffer
+    /// Voices playing a note, not counting stolen ones that are still fading out.
+    pub fn active_voices(&self) -> usize {
+        self.keys_pressed
+            .iter()
+            .filter(|v| v.stolen.is_none())
+            .count()
     }
 
-    pub fn samples(&self) -> u64 {
-        self.samples
+    /// No voice is sounding, not even one fading out.
+    pub fn is_silent(&self) -> bool {
+        self.keys_pressed.is_empty()
     }
 
-    pub fn next_sample(&mut self) -> f32 {
-        // self.sample_clock = (self.sample_clock + 1.0) % self.sample_rate; // this produces clicks every second
-        self.sample_clock += 1.0; // TODO: Fix, will go bad eventually
+    pub fn program_changes(&self) -> u32 {
+        self.program_changes
+    }
+
+    /// Highest polyphonic aftertouch among the sounding voices.
+    pub fn poly_pressure(&self) -> f32 {
+        self.keys_pressed
+            .iter()
+            .map(|v| v.pressure)
+            .fold(0.0, f32::max)
+    }
 
-        self.time += 1.0 / self.sample_rate;
+    /// Renders the channel's voices into a block of stereo frames, `left` and `right` have to
+    /// be the same length.
+    pub fn render_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        assert_eq!(left.len(), right.len());
 
-        let adsr = ADSR {
-            attack: 0.01,
-            decay: 0.4,
-            sustain: 0.5,
-            release: 0.6,
-        };
+        for (left, right) in left.chunks_mut(BLOCK).zip(right.chunks_mut(BLOCK)) {
+            self.render_block(left, right);
+        }
+    }
 
-        let mut sample = 0.0;
-        for voice in &self.keys_pressed {
-            let freq = voice.key.to_freq_f32();
+    /// Renders at most `BLOCK` frames. Pitch and partial gains are worked out once, with gains
+    /// ramping across the block while the spectrum morphs. Envelopes are evaluated per frame
+    /// into level buffers, and the inner loop only runs the oscillators.
+    fn render_block(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let frames = left.len();
+        left.iter_mut().for_each(|s| *s = 0.0);
+        right.iter_mut().for_each(|s| *s = 0.0);
 
-            let vol = if let Some(release_time) = voice.release_time {
-                adsr.evaluate(self.time - voice.time, self.time - release_time, false)
-                    * voice.velocity
-            } else {
-                adsr.evaluate(self.time - voice.time, 0.0, true) * voice.velocity
-            };
+        let patch = &self.patch;
+        let adsr = &patch.envelope;
+
+        let bend = 2.0f64
+            .powf((self.controllers.pitch_bend * self.controllers.pitch_bend_range) as f64 / 12.0);
+
+        let now = self.samples;
+        let sample_rate = self.sample_rate;
 
-            let partial_count = 64;
+        let partial_count = patch.partials.len();
+        let glide = (patch.glide * sample_rate) as f64;
+        let noise = &mut self.noise;
+        let controllers = self.controllers;
 
-            for (partial, &partial_volume) in self.partials.iter().enumerate() {
-                let partial = (partial + 1) as f32;
+        for voice in &mut self.keys_pressed {
+            let voice_pan = patch.voice_pan(u8::from(voice.key));
 
-                let x = partial * PI / (partial_count + 1) as f32;
-                let sigma = x.sin() / x; // Smoothes out wave, not always desirable
+            // Phase increment of the fundamental, in cycles per sample
+            let pitch = voice.pitch(now, glide);
+            let increment = frequency(pitch) * bend / sample_rate as f64;
 
-                sample += (1.0 / partial)
-                    * sigma
-                    * (partial * self.sample_clock * freq * 2.0 * PI / self.sample_rate).sin()
-                    * vol
-                    * partial_volume;
+            let (start, release_time, velocity) = (voice.time, voice.release_time, voice.velocity);
+            let since_on = |i: usize| (now + i as u64 - start) as f32 / sample_rate;
+            let since_release =
+                |i: usize| release_time.map(|r| (now + i as u64 - r) as f32 / sample_rate);
+
+            // Velocity, and the fade of a stolen voice
+            let mut scale = [velocity; BLOCK];
+            if let Some(stolen) = voice.stolen {
+                for (i, scale) in scale[..frames].iter_mut().enumerate() {
+                    let since = (now + i as u64 - stolen) as f32 / sample_rate;
+                    *scale *= (1.0 - since / STEAL_FADE).max(0.0);
+                }
             }
-        }
 
-        // Remove fully released voices
-        let time = self.time;
-        self.keys_pressed.retain(|v| {
-            if let Some(release_time) = v.release_time {
-                if time - release_time < adsr.release {
-                    true
+            let mut levels = [0.0; BLOCK];
+            for (i, level) in levels[..frames].iter_mut().enumerate() {
+                *level = adsr.evaluate(since_on(i), since_release(i)) * scale[i];
+            }
+
+            // How far the spectrum has morphed by the end of the block. Amplitudes move there
+            // from where the last block left off, so a moving source doesn't zipper
+            let morph = match patch.morph {
+                Some(MorphSource::Velocity) => voice.velocity,
+                Some(MorphSource::ModWheel) => controllers.mod_wheel,
+                Some(MorphSource::Aftertouch) => controllers.pressure.max(voice.pressure),
+                Some(MorphSource::Lfo) => {
+                    0.5 - 0.5 * (2.0 * PI * patch.morph_rate * since_on(frames)).cos()
+                }
+                None => 0.0,
+            };
+            let morph_from = voice.morph.replace(morph).unwrap_or(morph);
+
+            // Partials following the voice envelope are summed first and scaled once
+            let mut shared_left = [0.0; BLOCK];
+            let mut shared_right = [0.0; BLOCK];
+            let mut bank = Bank::new();
+
+            for ((index, p), phase) in patch.partials.iter().enumerate().zip(&mut voice.phases) {
+                let (amplitude, ratio) = patch.morphed(index, morph);
+                let (amplitude_from, _) = patch.morphed(index, morph_from);
+                let partial_increment = increment * ratio as f64;
+
+                // Silent partials keep their phase running so they come back in where they
+                // would be
+                let start_phase = *phase;
+                *phase = (*phase + partial_increment * frames as f64).fract();
+
+                // In cycles per sample, Nyquist is at 0.5
+                let of_nyquist = partial_increment / 0.5;
+                if (amplitude == 0.0 && amplitude_from == 0.0) || of_nyquist >= 1.0 {
+                    continue;
+                }
+                let fade = ((1.0 - of_nyquist) / (1.0 - NYQUIST_FADE)).min(1.0) as f32;
+
+                let partial = (index + 1) as f32;
+                let sigma = if patch.smoothing {
+                    // Lanczos sigma, tames the ringing of a truncated series
+                    let x = partial * PI / (partial_count + 1) as f32;
+                    x.sin() / x
                 } else {
-                    false
+                    1.0
+                };
+
+                let gain = (1.0 / partial) * sigma * fade * patch.volume;
+                let [gain_left, gain_right] = pan_gains(voice_pan + patch.spread_offset(index));
+                let gains = [gain * gain_left, gain * gain_right];
+
+                match &p.envelope {
+                    Some(envelope) => {
+                        let mut own_levels = [0.0; BLOCK];
+                        for (i, level) in own_levels[..frames].iter_mut().enumerate() {
+                            let progress = i as f32 / frames as f32;
+                            let amplitude =
+                                amplitude_from + (amplitude - amplitude_from) * progress;
+                            *level = envelope.evaluate(since_on(i), since_release(i))
+                                * scale[i]
+                                * amplitude;
+                        }
+                        oscillator::render_one(
+                            start_phase,
+                            partial_increment,
+                            gains,
+                            &own_levels[..frames],
+                            left,
+                            right,
+                        );
+                    }
+                    None => {
+                        if bank.is_full() {
+                            bank.flush(&mut shared_left[..frames], &mut shared_right[..frames]);
+                        }
+                        bank.push_ramp(
+                            start_phase,
+                            partial_increment,
+                            [gains[0] * amplitude_from, gains[1] * amplitude_from],
+                            [gains[0] * amplitude, gains[1] * amplitude],
+                        );
+                    }
                 }
-            } else {
-                true
             }
+            bank.flush(&mut shared_left[..frames], &mut shared_right[..frames]);
+
+            // Percussion dies away on its own, but stops with the note like the drawbars
+            if let (true, Some(percussion)) = (voice.percussion, patch.percussion) {
+                let percussion_increment = increment * percussion.ratio() as f64;
+                let start_phase = voice.percussion_phase;
+                voice.percussion_phase =
+                    (start_phase + percussion_increment * frames as f64).fract();
+
+                if percussion_increment < 0.5 {
+                    let mut percussion_levels = [0.0; BLOCK];
+                    for (i, level) in percussion_levels[..frames].iter_mut().enumerate() {
+                        *level = 10.0f32.powf(-3.0 * since_on(i) / percussion.decay());
+                    }
+
+                    let gain = percussion.level() / DRAWBAR_SCALE * patch.volume;
+                    let [gain_left, gain_right] = pan_gains(voice_pan);
+                    oscillator::render_one(
+                        start_phase,
+                        percussion_increment,
+                        [gain * gain_left, gain * gain_right],
+                        &percussion_levels[..frames],
+                        &mut shared_left[..frames],
+                        &mut shared_right[..frames],
+                    );
+                }
+            }
+
+            for i in 0..frames {
+                left[i] += shared_left[i] * levels[i];
+                right[i] += shared_right[i] * levels[i];
+            }
+
+            // A short burst of noise, fading out over `CLICK_TIME`
+            if patch.click > 0.0 && since_on(0) < CLICK_TIME {
+                let [gain_left, gain_right] = pan_gains(voice_pan);
+                let gain = patch.click * CLICK_GAIN / DRAWBAR_SCALE * patch.volume;
+                for i in 0..frames {
+                    let fade = (1.0 - since_on(i) / CLICK_TIME).max(0.0);
+                    if fade == 0.0 {
+                        break;
+                    }
+
+                    // xorshift32
+                    *noise ^= *noise << 13;
+                    *noise ^= *noise >> 17;
+                    *noise ^= *noise << 5;
+                    let sample = (*noise as f32 / u32::MAX as f32 * 2.0 - 1.0) * gain * fade;
+
+                    left[i] += sample * gain_left * scale[i];
+                    right[i] += sample * gain_right * scale[i];
+                }
+            }
+        }
+
+        self.samples += frames as u64;
+
+        // Remove fully released and faded out voices
+        let now = self.samples;
+        let release = (self.patch.release() * sample_rate) as u64;
+        let steal_fade = (STEAL_FADE * sample_rate) as u64;
+        self.keys_pressed.retain(|v| {
+            let released = match v.release_time {
+                Some(release_time) => now - release_time >= release,
+                None => false,
+            };
+            let faded = match v.stolen {
+                Some(stolen) => now - stolen >= steal_fade,
+                None => false,
+            };
+            !released && !faded
         });
+    }
 
-        self.sample_buffer.push(sample);
-        self.samples += 1;
+    /// Current level of a voice's envelope, including velocity.
+    fn level(&self, voice: &Voice) -> f32 {
+        let since_on = (self.samples - voice.time) as f32 / self.sample_rate;
+        let since_release = voice
+            .release_time
+            .map(|r| (self.samples - r) as f32 / self.sample_rate);
+        self.patch.envelope.evaluate(since_on, since_release) * voice.velocity
+    }
+
+    /// Picks the voice to make room for a new note on `key`, among those not already stolen.
+    fn victim(&self, key: Note) -> Option<usize> {
+        let candidates = || {
+            self.keys_pressed
+                .iter()
+                .enumerate()
+                .filter(|(_, v)| v.stolen.is_none())
+        };
+        let oldest = candidates().min_by_key(|(_, v)| v.time).map(|(i, _)| i);
 
-        sample
+        match self.patch.stealing {
+            Stealing::Oldest => oldest,
+            Stealing::Quietest => candidates()
+                .min_by(|(_, a), (_, b)| {
+                    self.level(a)
+                        .partial_cmp(&self.level(b))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                })
+                .map(|(i, _)| i),
+            Stealing::SameNote => candidates()
+                .filter(|(_, v)| v.key == key)
+                .min_by_key(|(_, v)| v.time)
+                .map(|(i, _)| i)
+                .or(oldest),
+            Stealing::ReleasedFirst => candidates()
+                .filter(|(_, v)| v.release_time.is_some())
+                .min_by_key(|(_, v)| v.time)
+                .map(|(i, _)| i)
+                .or(oldest),
+        }
     }
 
     pub fn toggle_key_down(&mut self, key: Note, vel: f32) {
-        self.keys_pressed.retain(|v| v.key != key);
+        if self.patch.mode != VoiceMode::Poly {
+            self.held_keys.retain(|&(k, _)| k != key);
+            self.held_keys.push((key, vel));
+            if let Some((key, vel)) = self.priority_key() {
+                self.play_mono(key, vel);
+            }
+            return;
+        }
+
+        // A re-struck key lets the previous voice ring out through its release
+        for v in &mut self.keys_pressed {
+            if v.key == key && v.release_time.is_none() {
+                v.key_down = false;
+                v.sustained = false;
+                v.sostenuto = false;
+                v.release_time = Some(self.samples);
+            }
+        }
+
+        self.start_voice(key, vel);
+    }
+
+    /// The held key a monophonic patch should be playing.
+    fn priority_key(&self) -> Option<(Note, f32)> {
+        let held = &self.held_keys;
+        match self.patch.priority {
+            Priority::Last => held.last(),
+            Priority::Low => held.iter().min_by_key(|&&(k, _)| k),
+            Priority::High => held.iter().max_by_key(|&&(k, _)| k),
+        }
+        .cloned()
+    }
+
+    /// Moves the single voice of a monophonic patch to `key`. Legato keeps the envelope going,
+    /// otherwise the old voice fades out as if stolen and a new one starts. Either way the pitch
+    /// glides over from the old note when the patch has a glide time.
+    fn play_mono(&mut self, key: Note, vel: f32) {
+        let now = self.samples;
+        let glide = (self.patch.glide * self.sample_rate) as f64;
+        let legato = self.patch.mode == VoiceMode::Legato;
+
+        let current = self
+            .keys_pressed
+            .iter_mut()
+            .find(|v| v.release_time.is_none() && v.stolen.is_none());
+
+        let from = match current {
+            Some(v) if v.key == key => return,
+            Some(v) => {
+                let from = v.pitch(now, glide);
+                if legato {
+                    v.glide = Some(Glide { from, start: now });
+                    v.key = key;
+                    v.key_down = true;
+                    return;
+                }
+                v.stolen = Some(now);
+                Some(from)
+            }
+            None => None,
+        };
+
+        self.start_voice(key, vel);
+        if let (Some(from), Some(voice)) = (from, self.keys_pressed.last_mut()) {
+            voice.glide = Some(Glide { from, start: now });
+        }
+    }
+
+    /// Adds a voice for `key`, stealing one first if the patch's polyphony is used up.
+    fn start_voice(&mut self, key: Note, vel: f32) {
+        let percussion = !self.keys_pressed.iter().any(|v| v.key_down && v.key != key);
+
+        let limit = self.patch.polyphony.clamp(1, MAX_POLYPHONY);
+        while self
+            .keys_pressed
+            .iter()
+            .filter(|v| v.stolen.is_none())
+            .count()
+            >= limit
+        {
+            match self.victim(key) {
+                Some(victim) => self.keys_pressed[victim].stolen = Some(self.samples),
+                None => break,
+            }
+        }
+
+        // A burst of notes can steal faster than the voices fade out, drop the oldest stolen
+        // voice instead of growing the vector on the audio thread
+        if self.keys_pressed.len() >= MAX_POLYPHONY * 2 {
+            if let Some(oldest) = (0..self.keys_pressed.len())
+                .filter(|&i| self.keys_pressed[i].stolen.is_some())
+                .min_by_key(|&i| self.keys_pressed[i].stolen)
+            {
+                self.keys_pressed.remove(oldest);
+            }
+        }
+
         self.keys_pressed.push({
             Voice {
                 key,
+                glide: None,
                 velocity: vel,
-                time: self.time,
+                time: self.samples,
                 release_time: None,
+                phases: [0.0; MAX_PARTIALS],
+                pressure: 0.0,
+                key_down: true,
+                sustained: false,
+                sostenuto: false,
+                stolen: None,
+                percussion,
+                percussion_phase: 0.0,
+                morph: None,
             }
         });
     }
 
     pub fn toggle_key_up(&mut self, key: Note) {
-        let v = self.keys_pressed.iter_mut().find(|v| v.key == key).unwrap();
-        v.release_time = Some(self.time);
+        if self.patch.mode != VoiceMode::Poly {
+            self.held_keys.retain(|&(k, _)| k != key);
+            // Fall back to a key that is still held, otherwise release as usual
+            if let Some((key, vel)) = self.priority_key() {
+                self.play_mono(key, vel);
+                return;
+            }
+        }
+
+        let sustain = self.controllers.sustain;
+        let time = self.samples;
+        if let Some(v) = self
+            .keys_pressed
+            .iter_mut()
+            .find(|v| v.key == key && v.key_down)
+        {
+            v.key_down = false;
+            v.sustained = sustain;
+            if !v.is_held() {
+                v.release_time = Some(time);
+            }
+        }
+    }
+
+    fn set_sustain(&mut self, down: bool) {
+        self.controllers.sustain = down;
+        if !down {
+            for v in &mut self.keys_pressed {
+                v.sustained = false;
+            }
+            self.release_unheld();
+        }
+    }
+
+    fn set_sostenuto(&mut self, down: bool) {
+        // Only latches on the transition, so repeated pedal messages don't pick up new keys
+        if down && !self.controllers.sostenuto {
+            for v in &mut self.keys_pressed {
+                v.sostenuto = v.key_down;
+            }
+        } else if !down {
+            for v in &mut self.keys_pressed {
+                v.sostenuto = false;
+            }
+            self.release_unheld();
+        }
+        self.controllers.sostenuto = down;
+    }
+
+    fn release_unheld(&mut self) {
+        for v in &mut self.keys_pressed {
+            if v.release_time.is_none() && !v.is_held() {
+                v.release_time = Some(self.samples);
+            }
+        }
+    }
+
+    /// Lets go of the pedals, releasing the voices they held, and resets the other controllers.
+    pub fn reset_controllers(&mut self) {
+        self.set_sustain(false);
+        self.set_sostenuto(false);
+        self.controllers.reset();
+    }
+
+    /// Releases every sounding voice, e.g. when playback stops.
+    pub fn release_all(&mut self) {
+        self.held_keys.clear();
+        for v in &mut self.keys_pressed {
+            v.key_down = false;
+            v.sustained = false;
+            v.sostenuto = false;
+        }
+        self.release_unheld();
+    }
+
+    pub fn handle_midi(&mut self, message: &MidiMessage) {
+        match *message {
+            // A note on with zero velocity is a note off by convention
+            MidiMessage::NoteOn(_, n, v) if u8::from(v) == 0 => self.toggle_key_up(n),
+            MidiMessage::NoteOn(_, n, v) => self.toggle_key_down(n, u8::from(v) as f32 / 127.0),
+            MidiMessage::NoteOff(_, n, _) => self.toggle_key_up(n),
+            MidiMessage::PolyphonicKeyPressure(_, n, v) => {
+                for voice in self.keys_pressed.iter_mut().filter(|voice| voice.key == n) {
+                    voice.pressure = u8::from(v) as f32 / 127.0;
+                }
+            }
+            MidiMessage::ChannelPressure(_, v) => {
+                self.controllers.pressure = u8::from(v) as f32 / 127.0;
+            }
+            MidiMessage::PitchBendChange(_, bend) => {
+                // Center is 8192, scale both directions to a full -1.0 to 1.0
+                let bend = u16::from(bend) as f32 - 8192.0;
+                self.controllers.pitch_bend = if bend < 0.0 {
+                    bend / 8192.0
+                } else {
+                    bend / (u16::from(U14::MAX) as f32 - 8192.0)
+                };
+            }
+            MidiMessage::ProgramChange(_, program) => {
+                self.controllers.program = u8::from(program);
+                self.program_changes = self.program_changes.wrapping_add(1);
+            }
+            MidiMessage::ControlChange(_, function, value) => {
+                let value = u8::from(value);
+                match function {
+                    ControlFunction::MODULATION_WHEEL => {
+                        self.controllers.mod_wheel = value as f32 / 127.0;
+                    }
+                    ControlFunction::DAMPER_PEDAL => self.set_sustain(value >= 64),
+                    ControlFunction::SOSTENUTO => self.set_sostenuto(value >= 64),
+                    ControlFunction::ALL_SOUND_OFF => self.keys_pressed.clear(),
+                    ControlFunction::RESET_ALL_CONTROLLERS => self.reset_controllers(),
+                    ControlFunction::ALL_NOTES_OFF => self.release_all(),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// All sixteen MIDI channels, each playing its own patch, mixed together.
+pub struct Synth {
+    sample_rate: f32,
+    pub channels: Vec<Channel>,
+    /// The factory bank, built up front so program changes only copy.
+    presets: Vec<Patch>,
+}
+
+impl Synth {
+    pub fn new(sample_rate: f32) -> Self {
+        Synth {
+            sample_rate,
+            channels: (0..CHANNELS).map(|_| Channel::new(sample_rate)).collect(),
+            presets: PRESETS.iter().map(|p| p.patch()).collect(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> f32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        for channel in &mut self.channels {
+            channel.sample_rate = sample_rate;
+        }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.channels.iter().all(Channel::is_silent)
+    }
+
+    /// Passes channel messages on to the channel they are addressed to. A program change
+    /// switches the channel to that factory preset.
+    pub fn handle_midi(&mut self, message: &MidiMessage) {
+        if let Some(channel) = message.channel() {
+            let channel = &mut self.channels[channel.index() as usize];
+            channel.handle_midi(message);
+
+            if let MidiMessage::ProgramChange(_, program) = message {
+                if let Some(preset) = self.presets.get(u8::from(*program) as usize) {
+                    channel.patch.copy_from(preset);
+                }
+            }
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        for channel in &mut self.channels {
+            channel.release_all();
+        }
+    }
+
+    pub fn reset_controllers(&mut self) {
+        for channel in &mut self.channels {
+            channel.reset_controllers();
+        }
+    }
+
+    /// Renders a block of mono samples, the average of both channels.
+    pub fn render(&mut self, out: &mut [f32]) {
+        let mut left = [0.0; BLOCK];
+        let mut right = [0.0; BLOCK];
+        for chunk in out.chunks_mut(BLOCK) {
+            let frames = chunk.len();
+            self.render_stereo(&mut left[..frames], &mut right[..frames]);
+            for ((sample, l), r) in chunk.iter_mut().zip(&left).zip(&right) {
+                *sample = (l + r) / 2.0;
+            }
+        }
+    }
+
+    /// Renders interleaved frames of `channels` samples. Left and right go into the first two
+    /// channels, further channels are silent and a single channel gets a mono mix.
+    pub fn render_interleaved(&mut self, out: &mut [f32], channels: usize) {
+        if channels == 1 {
+            return self.render(out);
+        }
+
+        let mut left = [0.0; BLOCK];
+        let mut right = [0.0; BLOCK];
+        for chunk in out.chunks_mut(BLOCK * channels) {
+            let frames = chunk.len() / channels;
+            self.render_stereo(&mut left[..frames], &mut right[..frames]);
+            for (i, frame) in chunk.chunks_mut(channels).enumerate() {
+                frame[0] = left[i];
+                frame[1] = right[i];
+                for sample in &mut frame[2..] {
+                    *sample = 0.0;
+                }
+            }
+        }
+    }
+
+    /// Like `render_interleaved`, with every message applied at its frame offset into `out`.
+    /// Messages have to be sorted by offset.
+    pub fn render_with_events<'a>(
+        &mut self,
+        out: &mut [f32],
+        channels: usize,
+        events: impl IntoIterator<Item = (usize, &'a MidiMessage<'static>)>,
+    ) {
+        let frames = out.len() / channels;
+
+        let mut done = 0;
+        for (offset, message) in events {
+            let offset = offset.min(frames);
+            self.render_interleaved(&mut out[done * channels..offset * channels], channels);
+            self.handle_midi(message);
+            done = offset;
+        }
+        self.render_interleaved(&mut out[done * channels..], channels);
+    }
+
+    /// Like `Channel::render_stereo`, mixing all channels together.
+    pub fn render_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        assert_eq!(left.len(), right.len());
+
+        let mut channel_left = [0.0; BLOCK];
+        let mut channel_right = [0.0; BLOCK];
+        for (left, right) in left.chunks_mut(BLOCK).zip(right.chunks_mut(BLOCK)) {
+            let frames = left.len();
+            left.iter_mut().for_each(|s| *s = 0.0);
+            right.iter_mut().for_each(|s| *s = 0.0);
+
+            for channel in &mut self.channels {
+                // Voices are timed against their own channel, so idle channels can skip ahead
+                if channel.is_silent() {
+                    continue;
+                }
+
+                channel.render_stereo(&mut channel_left[..frames], &mut channel_right[..frames]);
+                for i in 0..frames {
+                    left[i] += channel_left[i];
+                    right[i] += channel_right[i];
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::*;
+    use crate::envelope::{Adsr, Curve};
+    use crate::patch::{Partial, Percussion};
+
+    /// Holds a single sine for six minutes and measures its frequency at the end. That is past
+    /// 2^24 samples at 48 kHz, where the old f32 sample clock stopped counting exactly.
+    #[test]
+    fn stays_in_tune_on_long_notes() {
+        let sample_rate = 48_000usize;
+        let mut synth = Synth::new(sample_rate as f32);
+        synth.channels[0].patch.partials = vec![Partial {
+            amplitude: 1.0,
+            ratio: 1.0,
+            detune: 0.0,
+            envelope: None,
+        }];
+        synth.channels[0].patch.envelope = Adsr {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.0,
+            curve: Curve::Linear,
+        };
+        synth.channels[0].toggle_key_down(Note::A2, 1.0);
+
+        let mut block = vec![0.0; sample_rate];
+        for _ in 0..6 * 60 {
+            synth.render(&mut block);
+        }
+        assert!(synth.channels[0].samples > 1 << 24);
+
+        // Upward zero crossings over the last ten seconds, interpolated between samples
+        let mut tail = vec![0.0; 10 * sample_rate];
+        synth.render(&mut tail);
+
+        let mut crossings = vec![];
+        for (i, pair) in tail.windows(2).enumerate() {
+            let (previous, sample) = (pair[0], pair[1]);
+            if previous < 0.0 && sample >= 0.0 {
+                crossings.push(i as f64 + (previous / (previous - sample)) as f64);
+            }
+        }
+
+        let periods = (crossings.len() - 1) as f64;
+        let seconds = (crossings[crossings.len() - 1] - crossings[0]) / sample_rate as f64;
+        let frequency = periods / seconds;
+
+        assert!(
+            (frequency - Note::A2.to_freq_f64()).abs() < 0.01,
+            "measured {} Hz",
+            frequency
+        );
+    }
+
+    #[test]
+    fn steals_voices_over_the_limit() {
+        let mut synth = Synth::new(8000.0);
+        synth.channels[0].patch.polyphony = 2;
+        synth.channels[0].patch.stealing = Stealing::Oldest;
+
+        synth.channels[0].toggle_key_down(Note::C4, 1.0);
+        synth.channels[0].toggle_key_down(Note::E4, 1.0);
+        synth.channels[0].toggle_key_down(Note::G4, 1.0);
+        // The stolen voice is still fading out, but doesn't count against the limit
+        assert_eq!(synth.channels[0].keys_pressed.len(), 3);
+        assert_eq!(synth.channels[0].active_voices(), 2);
+
+        let mut out = vec![0.0; 800];
+        synth.render(&mut out);
+
+        assert_eq!(synth.channels[0].active_voices(), 2);
+        assert!(synth.channels[0]
+            .keys_pressed
+            .iter()
+            .all(|v| v.key != Note::C4));
+    }
+
+    #[test]
+    fn legato_moves_a_single_voice() {
+        let mut synth = Synth::new(8000.0);
+        synth.channels[0].patch.mode = VoiceMode::Legato;
+        synth.channels[0].patch.priority = Priority::Last;
+
+        synth.channels[0].toggle_key_down(Note::C4, 1.0);
+        synth.channels[0].toggle_key_down(Note::E4, 1.0);
+        assert_eq!(synth.channels[0].active_voices(), 1);
+        assert_eq!(synth.channels[0].keys_pressed[0].key, Note::E4);
+
+        // Letting go of the newer key returns to the one still held
+        synth.channels[0].toggle_key_up(Note::E4);
+        assert_eq!(synth.channels[0].active_voices(), 1);
+        assert_eq!(synth.channels[0].keys_pressed[0].key, Note::C4);
+        assert!(synth.channels[0].keys_pressed[0].release_time.is_none());
+
+        synth.channels[0].toggle_key_up(Note::C4);
+        assert!(synth.channels[0].keys_pressed[0].release_time.is_some());
+    }
+
+    #[test]
+    fn skips_partials_above_nyquist() {
+        let mut synth = Synth::new(8000.0);
+        synth.channels[0].patch.partials = vec![Partial {
+            amplitude: 1.0,
+            // 4400 Hz, above the 4000 Hz Nyquist limit
+            ratio: 10.0,
+            detune: 0.0,
+            envelope: None,
+        }];
+        synth.channels[0].toggle_key_down(Note::A4, 1.0);
+
+        let mut out = vec![0.0; 800];
+        synth.render(&mut out);
+        assert!(out.iter().all(|&s| s == 0.0));
+
+        synth.channels[0].patch.partials[0].ratio = 5.0;
+        synth.render(&mut out);
+        assert!(out.iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn routes_notes_by_channel() {
+        let mut synth = Synth::new(8000.0);
+        synth.handle_midi(&MidiMessage::NoteOn(
+            wmidi::Channel::Ch3,
+            Note::C4,
+            wmidi::U7::MAX,
+        ));
+
+        assert_eq!(synth.channels[2].active_voices(), 1);
+        assert_eq!(
+            synth
+                .channels
+                .iter()
+                .map(|c| c.active_voices())
+                .sum::<usize>(),
+            1
+        );
+    }
+
+    fn note(channel: &mut Channel, key: Note, down: bool) {
+        channel.handle_midi(&if down {
+            MidiMessage::NoteOn(wmidi::Channel::Ch1, key, wmidi::U7::MAX)
+        } else {
+            MidiMessage::NoteOff(wmidi::Channel::Ch1, key, wmidi::U7::MIN)
+        });
+    }
+
+    fn pedal(channel: &mut Channel, function: ControlFunction, down: bool) {
+        let value = if down { wmidi::U7::MAX } else { wmidi::U7::MIN };
+        channel.handle_midi(&MidiMessage::ControlChange(
+            wmidi::Channel::Ch1,
+            function,
+            value,
+        ));
+    }
+
+    /// Key and whether it was released, for every voice in order.
+    fn released(channel: &Channel) -> Vec<(Note, bool)> {
+        channel
+            .keys_pressed
+            .iter()
+            .map(|v| (v.key, v.release_time.is_some()))
+            .collect()
+    }
+
+    #[test]
+    fn note_bursts_keep_the_voices_in_place() {
+        let mut channel = Channel::new(8000.0);
+        channel.patch.polyphony = MAX_POLYPHONY;
+        let capacity = channel.keys_pressed.capacity();
+
+        // Nothing gets rendered, so stolen voices never fade out
+        for i in 0..4 * MAX_POLYPHONY {
+            let key = Note::try_from((i % 128) as u8).unwrap();
+            note(&mut channel, key, true);
+            note(&mut channel, key, false);
+        }
+
+        assert!(channel.keys_pressed.len() <= MAX_POLYPHONY * 2);
+        assert_eq!(channel.keys_pressed.capacity(), capacity);
+        assert_eq!(
+            channel
+                .keys_pressed
+                .iter()
+                .filter(|v| v.stolen.is_none())
+                .count(),
+            MAX_POLYPHONY
+        );
+    }
+
+    #[test]
+    fn sustain_puts_off_the_release() {
+        let mut channel = Channel::new(8000.0);
+        pedal(&mut channel, ControlFunction::DAMPER_PEDAL, true);
+        note(&mut channel, Note::C4, true);
+        note(&mut channel, Note::C4, false);
+        assert_eq!(released(&channel), vec![(Note::C4, false)]);
+
+        pedal(&mut channel, ControlFunction::DAMPER_PEDAL, false);
+        assert_eq!(released(&channel), vec![(Note::C4, true)]);
+    }
+
+    #[test]
+    fn sostenuto_holds_only_keys_already_down() {
+        let mut channel = Channel::new(8000.0);
+        note(&mut channel, Note::C4, true);
+        pedal(&mut channel, ControlFunction::SOSTENUTO, true);
+        note(&mut channel, Note::E4, true);
+        note(&mut channel, Note::C4, false);
+        note(&mut channel, Note::E4, false);
+        assert_eq!(
+            released(&channel),
+            vec![(Note::C4, false), (Note::E4, true)]
+        );
+
+        pedal(&mut channel, ControlFunction::SOSTENUTO, false);
+        assert_eq!(released(&channel), vec![(Note::C4, true), (Note::E4, true)]);
+    }
+
+    #[test]
+    fn restruck_sustained_key_rings_out() {
+        let mut channel = Channel::new(8000.0);
+        pedal(&mut channel, ControlFunction::DAMPER_PEDAL, true);
+        note(&mut channel, Note::C4, true);
+        note(&mut channel, Note::C4, false);
+        note(&mut channel, Note::C4, true);
+        assert_eq!(
+            released(&channel),
+            vec![(Note::C4, true), (Note::C4, false)]
+        );
+
+        // The new voice is sustained in turn
+        note(&mut channel, Note::C4, false);
+        assert_eq!(
+            released(&channel),
+            vec![(Note::C4, true), (Note::C4, false)]
+        );
+    }
+
+    #[test]
+    fn events_land_at_their_offsets() {
+        let mut synth = Synth::new(8000.0);
+        synth.channels[0].patch.envelope = Adsr {
+            attack: 0.0,
+            decay: 0.0,
+            sustain: 1.0,
+            release: 0.0,
+            curve: Curve::Linear,
+        };
+        let channel = wmidi::Channel::Ch1;
+        let note_on = MidiMessage::NoteOn(channel, Note::A4, wmidi::U7::MAX);
+        let sound_off =
+            MidiMessage::ControlChange(channel, ControlFunction::ALL_SOUND_OFF, wmidi::U7::MIN);
+
+        // Offsets in the middle of blocks, stereo frames
+        let mut out = vec![0.0; 2 * 400];
+        synth.render_with_events(&mut out, 2, vec![(100, &note_on), (300, &sound_off)]);
+
+        let frames = out.chunks(2).map(|f| f[0]).collect::<Vec<_>>();
+        assert!(frames[..100].iter().all(|&s| s == 0.0));
+        assert!(frames[100..110].iter().any(|&s| s != 0.0));
+        assert!(frames[290..300].iter().any(|&s| s != 0.0));
+        assert!(frames[300..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn resetting_controllers_lets_go_of_the_pedals() {
+        let mut synth = Synth::new(8000.0);
+        let channel = wmidi::Channel::Ch1;
+        synth.handle_midi(&MidiMessage::ControlChange(
+            channel,
+            ControlFunction::DAMPER_PEDAL,
+            wmidi::U7::MAX,
+        ));
+        synth.handle_midi(&MidiMessage::NoteOn(channel, Note::C4, wmidi::U7::MAX));
+        synth.handle_midi(&MidiMessage::NoteOff(channel, Note::C4, wmidi::U7::MIN));
+        assert!(synth.channels[0].keys_pressed[0].release_time.is_none());
+
+        // As the player does when it seeks
+        synth.reset_controllers();
+        assert!(!synth.channels[0].controllers.sustain);
+        assert!(synth.channels[0].keys_pressed[0].release_time.is_some());
+    }
+
+    #[test]
+    fn percussion_triggers_without_held_keys() {
+        let mut synth = Synth::new(8000.0);
+        let channel = &mut synth.channels[0];
+        channel.toggle_key_down(Note::C4, 1.0);
+        channel.toggle_key_down(Note::E4, 1.0);
+        channel.toggle_key_up(Note::C4);
+        channel.toggle_key_up(Note::E4);
+        channel.toggle_key_down(Note::G4, 1.0);
+
+        let percussion = channel
+            .keys_pressed
+            .iter()
+            .map(|v| v.percussion)
+            .collect::<Vec<_>>();
+        assert_eq!(percussion, vec![true, false, true]);
+    }
+
+    #[test]
+    fn percussion_decays_and_click_stays_short() {
+        let sample_rate = 48_000;
+        // 50 ms holds a whole number of periods of both A4 and its second harmonic
+        let window = sample_rate / 20;
+        let level = |samples: &[f32], frequency: f32| {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, s) in samples.iter().enumerate() {
+                let phase = 2.0 * PI * frequency * i as f32 / sample_rate as f32;
+                re += s * phase.cos();
+                im += s * phase.sin();
+            }
+            (re * re + im * im).sqrt() / samples.len() as f32
+        };
+
+        // Drawbars all in, so only the percussion sounds
+        let mut synth = Synth::new(sample_rate as f32);
+        synth.channels[0].patch.set_drawbars([0; 9]);
+        synth.channels[0].patch.percussion = Some(Percussion {
+            third: false,
+            soft: false,
+            fast: true,
+        });
+        synth.channels[0].toggle_key_down(Note::A4, 1.0);
+        let mut out = vec![0.0; window * 4];
+        synth.render(&mut out);
+
+        let first = level(&out[..window], 880.0);
+        assert!(first > 10.0 * level(&out[..window], 440.0));
+        assert!(first > 100.0 * level(&out[window * 3..], 880.0));
+
+        // The key click is over after `CLICK_TIME`
+        let mut synth = Synth::new(sample_rate as f32);
+        synth.channels[0].patch.set_drawbars([0; 9]);
+        synth.channels[0].patch.click = 1.0;
+        synth.channels[0].toggle_key_down(Note::A4, 1.0);
+        synth.render(&mut out);
+
+        let click = (CLICK_TIME * sample_rate as f32) as usize;
+        assert!(out[..click].iter().any(|&s| s != 0.0));
+        assert!(out[click..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn morphs_to_the_second_spectrum() {
+        let partial = |amplitude, ratio| Partial {
+            amplitude,
+            ratio,
+            detune: 0.0,
+            envelope: None,
+        };
+
+        let mut morphing = Synth::new(8000.0);
+        let channel = &mut morphing.channels[0];
+        channel.patch.partials = vec![partial(1.0, 1.0), partial(0.0, 2.0)];
+        channel.patch.morph_partials = vec![partial(0.2, 1.5), partial(0.7, 2.0)];
+        channel.patch.morph = Some(MorphSource::ModWheel);
+        channel.controllers.mod_wheel = 1.0;
+
+        // Fully morphed, it sounds like a patch made of the second spectrum
+        let mut target = Synth::new(8000.0);
+        target.channels[0].patch.partials = vec![partial(0.2, 1.5), partial(0.7, 2.0)];
+
+        let mut outputs = vec![];
+        for synth in [&mut morphing, &mut target] {
+            synth.channels[0].toggle_key_down(Note::A3, 1.0);
+            let mut out = vec![0.0; 256];
+            synth.render(&mut out);
+            outputs.push(out);
+        }
+
+        assert!(outputs[0].iter().any(|s| s.abs() > 0.01));
+        for (a, b) in outputs[0].iter().zip(&outputs[1]) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn program_change_loads_a_preset() {
+        let mut synth = Synth::new(8000.0);
+        let partials = synth.channels[1].patch.partials.as_ptr();
+
+        let organ = PRESETS
+            .iter()
+            .position(|p| p.name.starts_with("Organ"))
+            .unwrap();
+        synth.handle_midi(&MidiMessage::ProgramChange(
+            wmidi::Channel::Ch2,
+            wmidi::U7::try_from(organ as u8).unwrap(),
+        ));
+
+        assert_eq!(synth.channels[1].patch.partials.len(), 9);
+        assert_eq!(synth.channels[1].program_changes(), 1);
+        // Copied into the memory the channel already had
+        assert_eq!(synth.channels[1].patch.partials.as_ptr(), partials);
+        assert_eq!(synth.channels[0].patch.partials.len(), 64);
     }
 }
@@ -0,0 +1,136 @@
+use std::borrow::Cow;
+use std::fmt::Display;
+
+use imgui::*;
+
+use crate::audio::{self, AudioEngine};
+
+/// Buffer sizes offered for selection, when the device allows them.
+const BUFFER_SIZES: [u32; 7] = [64, 128, 256, 512, 1024, 2048, 4096];
+
+/// Combo box over `choices` plus a default entry, which stands for `None`. Returns whether the
+/// value was changed.
+fn choose<T>(ui: &Ui, label: &ImStr, value: &mut Option<T>, choices: &[T]) -> bool
+where
+    T: Clone + PartialEq + Display,
+{
+    let mut items = vec![None];
+    items.extend(choices.iter().cloned().map(Some));
+    // Keep showing a saved choice that is not available right now
+    if !items.contains(value) {
+        items.push(value.clone());
+    }
+
+    let mut current = items.iter().position(|item| item == value).unwrap_or(0);
+    let changed = ComboBox::new(label).build_simple(ui, &mut current, &items, &|item| {
+        Cow::Owned(ImString::new(match item {
+            Some(item) => item.to_string(),
+            None => String::from("default"),
+        }))
+    });
+
+    if changed {
+        *value = items[current].clone();
+    }
+    changed
+}
+
+pub fn draw_audio_panel(ui: &Ui, engine: &mut AudioEngine) {
+    Window::new(im_str!("audio"))
+        .position([10.0, 200.0], Condition::FirstUseEver)
+        .always_auto_resize(true)
+        .build(ui, || {
+            match engine.device() {
+                Some((device, sample_rate)) => {
+                    ui.text(format!("playing on {} at {} Hz", device, sample_rate))
+                }
+                None => ui.text("stopped"),
+            }
+
+            let mut settings = engine.settings().clone();
+
+            let hosts = audio::host_names()
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<_>>();
+            if choose(ui, im_str!("host"), &mut settings.host, &hosts) {
+                // Device names belong to a host
+                settings.device = None;
+            }
+
+            let names = engine
+                .devices()
+                .iter()
+                .map(|d| d.name.clone())
+                .collect::<Vec<_>>();
+            choose(ui, im_str!("device"), &mut settings.device, &names);
+
+            let device = settings
+                .device
+                .as_deref()
+                .or_else(|| engine.default_device());
+            let info = engine
+                .devices()
+                .iter()
+                .find(|d| Some(d.name.as_str()) == device);
+
+            let sample_rates = info.map(|d| d.sample_rates.clone()).unwrap_or_default();
+            choose(
+                ui,
+                im_str!("sample rate"),
+                &mut settings.sample_rate,
+                &sample_rates,
+            );
+
+            let buffer_sizes = BUFFER_SIZES
+                .iter()
+                .cloned()
+                .filter(|&size| match info.and_then(|d| d.buffer_sizes) {
+                    Some((min, max)) => min <= size && size <= max,
+                    None => true,
+                })
+                .collect::<Vec<_>>();
+            choose(
+                ui,
+                im_str!("buffer size"),
+                &mut settings.buffer_size,
+                &buffer_sizes,
+            );
+
+            let mut result = Ok(());
+
+            if &settings != engine.settings() {
+                result = engine.configure(settings.clone());
+                // Only settings that work are kept for the next launch
+                if result.is_ok() {
+                    if let Err(e) = settings.save() {
+                        eprintln!("failed to save audio settings: {}", e);
+                    }
+                }
+            }
+
+            if engine.is_running() {
+                if ui.button(im_str!("Stop"), [0.0, 0.0]) {
+                    engine.stop();
+                }
+            } else if ui.button(im_str!("Start"), [0.0, 0.0]) {
+                result = engine.start();
+            }
+            ui.same_line(0.0);
+            if ui.button(im_str!("Reopen"), [0.0, 0.0]) {
+                result = engine.restart();
+            }
+            ui.same_line(0.0);
+            if ui.button(im_str!("Refresh devices"), [0.0, 0.0]) {
+                engine.refresh();
+            }
+
+            if let Err(e) = result {
+                engine.error = Some(e.to_string());
+            }
+
+            if let Some(error) = &engine.error {
+                ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+            }
+        });
+}
@@ -0,0 +1,176 @@
+use imgui::*;
+
+use crate::envelope::{Adsr, Curve};
+
+const WIDTH: f32 = 400.0;
+const HEIGHT: f32 = 150.0;
+
+/// Longest attack, decay or release the editor can set, in seconds.
+const MAX_TIME: f32 = 4.0;
+
+/// Breakpoints that can be dragged around.
+#[derive(Clone, Copy)]
+enum Breakpoint {
+    /// End of the attack, moves horizontally.
+    Peak,
+    /// End of the decay, moves in both directions.
+    Decay,
+    /// End of the held sustain, moves vertically.
+    Sustain,
+    /// End of the release, moves horizontally.
+    Release,
+}
+
+const BREAKPOINTS: [Breakpoint; 4] = [
+    Breakpoint::Peak,
+    Breakpoint::Decay,
+    Breakpoint::Sustain,
+    Breakpoint::Release,
+];
+
+/// The canvas is split into four equal lanes for attack, decay, sustain and release, so moving
+/// one breakpoint doesn't rescale the others. Times are mapped with a square root to give short
+/// times more room.
+fn time_to_x(t: f32) -> f32 {
+    (t / MAX_TIME).clamp(0.0, 1.0).sqrt() * WIDTH / 4.0
+}
+
+fn x_to_time(x: f32) -> f32 {
+    (x / (WIDTH / 4.0)).clamp(0.0, 1.0).powi(2) * MAX_TIME
+}
+
+pub struct EnvelopeEditor {
+    dragging: Option<Breakpoint>,
+}
+
+impl EnvelopeEditor {
+    pub fn new() -> Self {
+        EnvelopeEditor { dragging: None }
+    }
+
+    fn position(envelope: &Adsr, breakpoint: Breakpoint) -> [f32; 2] {
+        let lane = WIDTH / 4.0;
+        let sustain_y = (1.0 - envelope.sustain) * HEIGHT;
+        match breakpoint {
+            Breakpoint::Peak => [time_to_x(envelope.attack), 0.0],
+            Breakpoint::Decay => [lane + time_to_x(envelope.decay), sustain_y],
+            Breakpoint::Sustain => [lane * 3.0, sustain_y],
+            Breakpoint::Release => [lane * 3.0 + time_to_x(envelope.release), HEIGHT],
+        }
+    }
+
+    /// Returns whether the envelope was changed.
+    pub fn edit(&mut self, ui: &Ui, envelope: &mut Adsr) -> bool {
+        let mut changed = false;
+
+        let origin = ui.cursor_screen_pos();
+        ui.invisible_button(im_str!("envelope canvas"), [WIDTH, HEIGHT]);
+
+        let [m_x, m_y] = ui.io().mouse_pos;
+        let mouse = [m_x - origin[0], m_y - origin[1]];
+
+        if ui.is_item_clicked(MouseButton::Left) {
+            self.dragging = BREAKPOINTS.iter().cloned().find(|&b| {
+                let [x, y] = Self::position(envelope, b);
+                (x - mouse[0]).powi(2) + (y - mouse[1]).powi(2) < 10.0f32.powi(2)
+            });
+        }
+        if !ui.is_mouse_down(MouseButton::Left) {
+            self.dragging = None;
+        }
+
+        if let Some(breakpoint) = self.dragging {
+            let lane = WIDTH / 4.0;
+            let level = (1.0 - mouse[1] / HEIGHT).clamp(0.0, 1.0);
+            match breakpoint {
+                Breakpoint::Peak => envelope.attack = x_to_time(mouse[0]),
+                Breakpoint::Decay => {
+                    envelope.decay = x_to_time(mouse[0] - lane);
+                    envelope.sustain = level;
+                }
+                Breakpoint::Sustain => envelope.sustain = level,
+                Breakpoint::Release => envelope.release = x_to_time(mouse[0] - lane * 3.0),
+            }
+            changed = true;
+        }
+
+        let draw_list = ui.get_window_draw_list();
+
+        draw_list
+            .add_rect(
+                origin,
+                [origin[0] + WIDTH, origin[1] + HEIGHT],
+                [0.3, 0.3, 0.3],
+            )
+            .build();
+
+        // Sample the envelope across each lane
+        let steps = 32;
+        let lane = WIDTH / 4.0;
+        let mut points = vec![];
+        for i in 0..=steps {
+            let x = i as f32 / steps as f32;
+            let t = x_to_time(x * lane);
+            points.push((x * lane, envelope.held(t.min(envelope.attack))));
+        }
+        for i in 0..=steps {
+            let x = i as f32 / steps as f32;
+            let t = x_to_time(x * lane);
+            let level = envelope.held(envelope.attack + t.min(envelope.decay));
+            points.push((lane + x * lane, level));
+        }
+        for i in 0..=steps {
+            let x = i as f32 / steps as f32;
+            let t = x_to_time(x * lane).min(envelope.release);
+            // Released from the sustain level
+            let level = envelope.evaluate(envelope.attack + envelope.decay + t, Some(t));
+            points.push((lane * 3.0 + x * lane, level));
+        }
+
+        for pair in points.windows(2) {
+            let (x1, l1) = pair[0];
+            let (x2, l2) = pair[1];
+            draw_list
+                .add_line(
+                    [origin[0] + x1, origin[1] + (1.0 - l1) * HEIGHT],
+                    [origin[0] + x2, origin[1] + (1.0 - l2) * HEIGHT],
+                    [1.0, 1.0, 1.0],
+                )
+                .build();
+        }
+
+        for &breakpoint in BREAKPOINTS.iter() {
+            let [x, y] = Self::position(envelope, breakpoint);
+            draw_list
+                .add_circle([origin[0] + x, origin[1] + y], 4.0, [1.0, 0.4, 0.4])
+                .filled(true)
+                .build();
+        }
+
+        changed |= Slider::new(im_str!("attack"))
+            .range(0.0..=MAX_TIME)
+            .display_format(im_str!("%.3f s"))
+            .build(ui, &mut envelope.attack);
+        changed |= Slider::new(im_str!("decay"))
+            .range(0.0..=MAX_TIME)
+            .display_format(im_str!("%.3f s"))
+            .build(ui, &mut envelope.decay);
+        changed |= Slider::new(im_str!("sustain"))
+            .range(0.0..=1.0)
+            .build(ui, &mut envelope.sustain);
+        changed |= Slider::new(im_str!("release"))
+            .range(0.0..=MAX_TIME)
+            .display_format(im_str!("%.3f s"))
+            .build(ui, &mut envelope.release);
+
+        changed |= ui.radio_button(im_str!("linear"), &mut envelope.curve, Curve::Linear);
+        ui.same_line(0.0);
+        changed |= ui.radio_button(
+            im_str!("exponential"),
+            &mut envelope.curve,
+            Curve::Exponential,
+        );
+
+        changed
+    }
+}
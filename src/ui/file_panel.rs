@@ -0,0 +1,47 @@
+use std::path::PathBuf;
+
+use imgui::*;
+
+pub enum FileAction {
+    Open(PathBuf),
+    ShowLive,
+}
+
+pub struct FilePanel {
+    path: ImString,
+    pub error: Option<String>,
+}
+
+impl FilePanel {
+    pub fn new() -> Self {
+        FilePanel {
+            path: ImString::with_capacity(256),
+            error: None,
+        }
+    }
+
+    pub fn draw(&mut self, ui: &Ui) -> Option<FileAction> {
+        let mut action = None;
+
+        Window::new(im_str!("file"))
+            .position([10.0, 10.0], Condition::FirstUseEver)
+            .always_auto_resize(true)
+            .build(ui, || {
+                ui.input_text(im_str!("midi file"), &mut self.path).build();
+
+                if ui.button(im_str!("Open"), [0.0, 0.0]) {
+                    action = Some(FileAction::Open(PathBuf::from(self.path.to_str())));
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Live"), [0.0, 0.0]) {
+                    action = Some(FileAction::ShowLive);
+                }
+
+                if let Some(error) = &self.error {
+                    ui.text_colored([1.0, 0.3, 0.3, 1.0], error);
+                }
+            });
+
+        action
+    }
+}
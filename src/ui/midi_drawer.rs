@@ -1,45 +1,70 @@
 use imgui::Ui;
 use wmidi::Note;
 
-use crate::midi::MidiEvent;
+use crate::midi::Track;
+
+const TRACK_COLORS: [[f32; 3]; 6] = [
+    [1.0, 1.0, 1.0],
+    [0.4, 0.8, 1.0],
+    [1.0, 0.6, 0.3],
+    [0.5, 1.0, 0.5],
+    [1.0, 0.5, 0.8],
+    [1.0, 1.0, 0.4],
+];
+
+/// Draws every track between `start` and `end`, in microseconds. Notes that are still held are
+/// drawn up until `end`.
+pub fn draw_midi_viewer(
+    ui: &Ui,
+    tracks: &[Track],
+    start: u64,
+    end: u64,
+    playhead: Option<u64>,
+    width: f32,
+    height: f32,
+) {
+    if tracks.iter().any(|t| !t.events.is_empty()) {
+        let mut note_draw_list = vec![];
 
-pub fn draw_midi_viewer(ui: &Ui, notes: &[MidiEvent], current_time: u64, width: f32, height: f32) {
-    if notes.len() >= 1 {
-        let mut notes_on = vec![];
+        let (mut lowest, mut highest) = (u8::MAX, u8::MIN);
 
-        let mut note_draw_list = vec![];
+        for (track_idx, track) in tracks.iter().enumerate() {
+            let mut notes_on = vec![];
 
-        let mut note_range = 255..0;
+            for note in &track.events {
+                match note.input {
+                    wmidi::MidiMessage::NoteOn(_, n, v) if u8::from(v) > 0 => {
+                        notes_on.push((note.time, n));
 
-        for note in notes {
-            match note.input {
-                wmidi::MidiMessage::NoteOff(_, n, _) => {
-                    let start_note = notes_on.swap_remove(
-                        notes_on
-                            .iter()
-                            .position(|i: &(u64, Note)| i.1 == n)
-                            .unwrap(),
-                    );
+                        lowest = u8::from(n).min(lowest);
+                        highest = u8::from(n).max(highest);
+                    }
+                    wmidi::MidiMessage::NoteOff(_, n, _) | wmidi::MidiMessage::NoteOn(_, n, _) => {
+                        if let Some(idx) = notes_on.iter().position(|i: &(u64, Note)| i.1 == n) {
+                            let start_note = notes_on.swap_remove(idx);
 
-                    note_draw_list.push((start_note.0, note.time, n));
+                            note_draw_list.push((start_note.0, note.time, n, track_idx));
+                        }
+                    }
+                    _ => {}
                 }
-                wmidi::MidiMessage::NoteOn(_, n, _) => {
-                    notes_on.push((note.time, n));
+            }
 
-                    note_range.start = u8::from(n).min(note_range.start);
-                    note_range.end = u8::from(n).max(note_range.end);
-                }
-                _ => {}
+            for (time, note) in notes_on {
+                note_draw_list.push((time, end, note, track_idx));
             }
         }
 
+        if lowest > highest {
+            return;
+        }
+
         let draw_list = ui.get_window_draw_list();
 
-        let start = notes[0].time as f32;
-        let end = current_time as f32;
-        let len = end - start as f32;
+        let start = start as f32;
+        let len = (end as f32 - start).max(1.0);
 
-        let displayed_note_range = note_range.clone().count() + 24;
+        let displayed_note_range = (highest - lowest) as usize + 24;
 
         let s_x = width / len;
         let s_y = height / displayed_note_range as f32;
@@ -54,11 +79,7 @@ pub fn draw_midi_viewer(ui: &Ui, notes: &[MidiEvent], current_time: u64, width:
                 .build();
         }
 
-        for (time, note) in notes_on {
-            note_draw_list.push((time, current_time, note));
-        }
-
-        for (t1, t2, note) in note_draw_list {
+        for (t1, t2, note, track_idx) in note_draw_list {
             let t1 = (t1 as f32 - start) * s_x;
             let t2 = (t2 as f32 - start) * s_x;
 
@@ -66,15 +87,30 @@ pub fn draw_midi_viewer(ui: &Ui, notes: &[MidiEvent], current_time: u64, width:
 
             draw_list
                 .add_rect(
-                    [t1, height - (n - note_range.start as f32 + 12.0) * s_y],
-                    [
-                        t2,
-                        height - (n - note_range.start as f32 + 1.0 + 12.0) * s_y,
-                    ],
-                    [1.0, 1.0, 1.0],
+                    [t1, height - (n - lowest as f32 + 12.0) * s_y],
+                    [t2, height - (n - lowest as f32 + 1.0 + 12.0) * s_y],
+                    TRACK_COLORS[track_idx % TRACK_COLORS.len()],
                 )
                 .filled(true)
                 .build();
         }
+
+        if let Some(playhead) = playhead {
+            let x = (playhead as f32 - start) * s_x;
+            draw_list
+                .add_line([x, 0.0], [x, height], [1.0, 0.2, 0.2])
+                .thickness(2.0)
+                .build();
+        }
+
+        if tracks.len() > 1 {
+            for (track_idx, track) in tracks.iter().enumerate() {
+                draw_list.add_text(
+                    [width - 150.0, 4.0 + track_idx as f32 * 14.0],
+                    TRACK_COLORS[track_idx % TRACK_COLORS.len()],
+                    &track.name,
+                );
+            }
+        }
     }
 }
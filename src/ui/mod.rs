@@ -1 +1,8 @@
-pub mod midi_drawer;
\ No newline at end of file
+pub mod audio_panel;
+pub mod envelope_editor;
+pub mod file_panel;
+pub mod midi_drawer;
+pub mod partial_editor;
+pub mod patch_browser;
+pub mod recorder;
+pub mod transport;
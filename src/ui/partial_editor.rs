@@ -0,0 +1,581 @@
+use imgui::*;
+
+use crate::patch::{
+    MorphSource, Partial, Patch, Percussion, Priority, Stealing, VoiceMode, MAX_POLYPHONY,
+};
+use crate::remote::{ChannelStatus, Status};
+use crate::ui::envelope_editor::EnvelopeEditor;
+
+/// Height of the strip showing how far each partial is tuned away from its harmonic.
+const TUNING_STRIP: f32 = 40.0;
+
+/// Deviation at which the tuning strip is full scale.
+const TUNING_RANGE_CENTS: f32 = 100.0;
+
+/// Room below the drawbars for their footages and the readout.
+const DRAWBAR_LABELS: f32 = 40.0;
+
+const FOOTAGES: [&str; 9] = [
+    "16'", "5 1/3'", "8'", "4'", "2 2/3'", "2'", "1 3/5'", "1 1/3'", "1'",
+];
+
+/// Brown for the sub-harmonic drawbars, white for octaves of the fundamental and black for the
+/// other harmonics, like on the organ.
+const DRAWBAR_COLORS: [[f32; 3]; 9] = [
+    [0.55, 0.3, 0.15],
+    [0.55, 0.3, 0.15],
+    [0.9, 0.9, 0.9],
+    [0.9, 0.9, 0.9],
+    [0.35, 0.35, 0.35],
+    [0.9, 0.9, 0.9],
+    [0.35, 0.35, 0.35],
+    [0.35, 0.35, 0.35],
+    [0.9, 0.9, 0.9],
+];
+
+/// Registration a patch starts with when switched to drawbars.
+const DEFAULT_REGISTRATION: [u8; 9] = [8, 8, 8, 0, 0, 0, 0, 0, 0];
+
+pub struct PartialEditor {
+    /// Channel whose patch is being edited, 0 to 15.
+    channel: usize,
+    /// Partial whose envelope and tuning is being edited, right click a bar to select it.
+    selected: Option<usize>,
+    /// The bars edit the spectrum the patch morphs to, instead of its partials.
+    editing_morph: bool,
+    tilt: f32,
+    inharmonicity: f32,
+}
+
+impl PartialEditor {
+    pub fn new() -> Self {
+        PartialEditor {
+            channel: 0,
+            selected: None,
+            editing_morph: false,
+            tilt: 0.1,
+            inharmonicity: 0.0001,
+        }
+    }
+
+    pub fn channel(&self) -> usize {
+        self.channel
+    }
+
+    /// The spectrum being edited, A or B.
+    fn spectrum<'a>(&self, patch: &'a mut Patch) -> &'a mut Vec<Partial> {
+        if self.editing_morph && patch.morph.is_some() {
+            &mut patch.morph_partials
+        } else {
+            &mut patch.partials
+        }
+    }
+
+    /// Draws the amplitude bars of the edited channel into the current window, which spans
+    /// `origin` to `origin + size`, or its drawbars if it has them. Dragging with the left
+    /// button draws amplitudes. Returns whether the patch was changed.
+    pub fn draw(
+        &mut self,
+        ui: &Ui,
+        patches: &mut [Patch],
+        status: &Status,
+        origin: [f32; 2],
+        size: [f32; 2],
+    ) -> bool {
+        let patch = &mut patches[self.channel];
+        let status = &status.channels[self.channel];
+
+        // The patch may have been replaced by one with fewer partials
+        if self.selected >= Some(patch.partials.len()) {
+            self.selected = None;
+        }
+        let mut changed = patch.morph.is_some() && patch.match_morph_partials();
+
+        if let Some(levels) = patch.drawbars {
+            self.selected = None;
+            let [width, height] = size;
+            changed |=
+                Self::draw_drawbars(ui, patch, levels, origin, [width, height - DRAWBAR_LABELS]);
+            self.draw_readout(
+                &ui.get_window_draw_list(),
+                patch.polyphony,
+                status,
+                origin,
+                size,
+            );
+            return changed;
+        }
+        if patch.partials.is_empty() {
+            return changed;
+        }
+
+        let [x, y] = origin;
+        let [width, height] = size;
+
+        let partial_count = patch.partials.len();
+        let bar_width = width / partial_count as f32;
+
+        let draw_list = ui.get_window_draw_list();
+        self.draw_readout(&draw_list, patch.polyphony, status, origin, size);
+
+        // The other spectrum shows through as outlines, to compare A and B
+        if patch.morph.is_some() {
+            let other = if self.editing_morph {
+                &patch.partials
+            } else {
+                &patch.morph_partials
+            };
+            for (partial, p) in other.iter().enumerate() {
+                let top = y + (1.0 - p.amplitude) * height;
+                draw_list
+                    .add_line(
+                        [x + partial as f32 * bar_width, top],
+                        [x + (partial + 1) as f32 * bar_width, top],
+                        [0.5, 0.5, 0.5],
+                    )
+                    .build();
+            }
+        }
+
+        let editing_morph = self.editing_morph && patch.morph.is_some();
+        let partials = self.spectrum(patch);
+        for (partial, p) in partials.iter().enumerate() {
+            let color = if editing_morph {
+                [1.0, 0.6, 0.3]
+            } else if p.envelope.is_some() {
+                [0.4, 0.8, 1.0]
+            } else {
+                [1.0, 1.0, 1.0]
+            };
+
+            draw_list
+                .add_rect(
+                    [
+                        x + partial as f32 * bar_width,
+                        y + (1.0 - p.amplitude) * height,
+                    ],
+                    [x + (partial + 1) as f32 * bar_width, y + height],
+                    color,
+                )
+                .filled(self.selected == Some(partial))
+                .build();
+        }
+
+        // Deviation from the harmonic series, in cents
+        let center = y + TUNING_STRIP / 2.0;
+        draw_list
+            .add_line([x, center], [x + width, center], [0.3, 0.3, 0.3])
+            .build();
+        for (partial, p) in partials.iter().enumerate() {
+            let cents = 1200.0 * (p.frequency_ratio() / (partial + 1) as f32).log2();
+            if cents.abs() < 0.5 {
+                continue;
+            }
+
+            let deviation = (cents / TUNING_RANGE_CENTS).clamp(-1.0, 1.0) * TUNING_STRIP / 2.0;
+            draw_list
+                .add_rect(
+                    [x + partial as f32 * bar_width + 1.0, center],
+                    [
+                        x + (partial + 1) as f32 * bar_width - 1.0,
+                        center - deviation,
+                    ],
+                    [1.0, 0.8, 0.3],
+                )
+                .filled(true)
+                .build();
+        }
+
+        let [p_x, p_y] = ui.io().mouse_pos;
+        if !ui.is_window_hovered() || p_x <= x || p_x >= x + width || p_y <= y || p_y >= y + height
+        {
+            return changed;
+        }
+
+        let partial = ((p_x - x) / bar_width) as usize;
+
+        let p = &partials[partial];
+        ui.tooltip_text(format!(
+            "partial {}\namplitude {:.2}\nratio {:.3}\ndetune {:+.1} cents",
+            partial + 1,
+            p.amplitude,
+            p.ratio,
+            p.detune
+        ));
+
+        if ui.is_mouse_down(MouseButton::Left) {
+            partials[partial].amplitude = 1.0 - (p_y - y) / height;
+            changed = true;
+        }
+
+        if ui.is_mouse_clicked(MouseButton::Right) {
+            self.selected = if self.selected == Some(partial) {
+                None
+            } else {
+                Some(partial)
+            };
+        }
+
+        changed
+    }
+
+    /// Voice count and controller state along the bottom edge.
+    fn draw_readout(
+        &self,
+        draw_list: &WindowDrawList,
+        polyphony: usize,
+        status: &ChannelStatus,
+        origin: [f32; 2],
+        size: [f32; 2],
+    ) {
+        let [x, y] = origin;
+        let height = size[1];
+
+        let c = &status.controllers;
+        draw_list.add_text(
+            [x + 4.0, y + height - 16.0],
+            [1.0, 0.4, 0.4],
+            format!(
+                "channel {}  voices {}/{}  bend {:+.2}  mod {:.2}  pressure {:.2}/{:.2}  sustain {}  sostenuto {}  program {}",
+                self.channel + 1,
+                status.active_voices,
+                polyphony,
+                c.pitch_bend,
+                c.mod_wheel,
+                c.pressure,
+                status.poly_pressure,
+                if c.sustain { "on" } else { "off" },
+                if c.sostenuto { "on" } else { "off" },
+                c.program + 1,
+            ),
+        );
+    }
+
+    /// Nine drawbars hanging from the top, pulled further down for louder, in eight steps.
+    /// Dragging with the left button pulls the drawbar under the mouse to that step.
+    fn draw_drawbars(
+        ui: &Ui,
+        patch: &mut Patch,
+        mut levels: [u8; 9],
+        origin: [f32; 2],
+        size: [f32; 2],
+    ) -> bool {
+        let [x, y] = origin;
+        let [width, height] = size;
+
+        let bar_width = width / levels.len() as f32;
+        let step = height / 8.0;
+
+        let draw_list = ui.get_window_draw_list();
+        for (i, &level) in levels.iter().enumerate() {
+            let left = x + (i as f32 + 0.25) * bar_width;
+            let right = x + (i as f32 + 0.75) * bar_width;
+
+            draw_list
+                .add_rect([left, y], [right, y + height], [0.3, 0.3, 0.3])
+                .build();
+            draw_list
+                .add_rect(
+                    [left, y],
+                    [right, y + level as f32 * step],
+                    DRAWBAR_COLORS[i],
+                )
+                .filled(true)
+                .build();
+            draw_list.add_text(
+                [left, y + height + 4.0],
+                [1.0, 1.0, 1.0],
+                format!("{} {}", FOOTAGES[i], level),
+            );
+        }
+
+        let [p_x, p_y] = ui.io().mouse_pos;
+        if !ui.is_window_hovered() || p_x <= x || p_x >= x + width || p_y <= y || p_y >= y + height
+        {
+            return false;
+        }
+
+        let drawbar = (((p_x - x) / bar_width) as usize).min(levels.len() - 1);
+        ui.tooltip_text(format!("{} at {}", FOOTAGES[drawbar], levels[drawbar]));
+
+        if ui.is_mouse_down(MouseButton::Left) {
+            let level = ((p_y - y) / step).round().clamp(0.0, 8.0) as u8;
+            if level != levels[drawbar] {
+                levels[drawbar] = level;
+                patch.set_drawbars(levels);
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Picks the channel to edit. Edits the tuning and envelope of the selected partial, or the
+    /// envelope of the whole patch when there is no selection. Returns whether the patch was
+    /// changed.
+    pub fn draw_selection(
+        &mut self,
+        ui: &Ui,
+        editor: &mut EnvelopeEditor,
+        patches: &mut [Patch],
+    ) -> bool {
+        let mut changed = false;
+
+        let mut channel = self.channel as i32 + 1;
+        if Slider::new(im_str!("channel"))
+            .range(1..=patches.len() as i32)
+            .build(ui, &mut channel)
+        {
+            self.channel = channel as usize - 1;
+            self.selected = None;
+        }
+        let patch = &mut patches[self.channel];
+
+        match self.selected {
+            Some(selected) => {
+                ui.text(format!("partial {}", selected + 1));
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("deselect")) {
+                    self.selected = None;
+                }
+
+                let patch_envelope = patch.envelope;
+                let editing_morph = self.editing_morph && patch.morph.is_some();
+                let partial = &mut self.spectrum(patch)[selected];
+
+                changed |= Drag::new(im_str!("ratio"))
+                    .range(0.01..=128.0)
+                    .speed(0.001)
+                    .display_format(im_str!("%.4f"))
+                    .build(ui, &mut partial.ratio);
+                changed |= Slider::new(im_str!("detune"))
+                    .range(-100.0..=100.0)
+                    .display_format(im_str!("%.1f cents"))
+                    .build(ui, &mut partial.detune);
+
+                if editing_morph {
+                    ui.text("in B, the envelope is the one of the partial in A");
+                } else {
+                    let mut own = partial.envelope.is_some();
+                    if ui.checkbox(im_str!("own envelope"), &mut own) {
+                        partial.envelope = if own { Some(patch_envelope) } else { None };
+                        changed = true;
+                    }
+
+                    match &mut partial.envelope {
+                        Some(envelope) => changed |= editor.edit(ui, envelope),
+                        None => ui.text("follows the patch envelope"),
+                    }
+                }
+            }
+            None => {
+                ui.text("patch envelope, right click a partial to edit its own");
+                changed |= editor.edit(ui, &mut patch.envelope);
+            }
+        }
+
+        ui.separator();
+
+        let mut drawbars = patch.drawbars.is_some();
+        if ui.checkbox(im_str!("drawbars"), &mut drawbars) {
+            if drawbars {
+                patch.set_drawbars(DEFAULT_REGISTRATION);
+                self.selected = None;
+                self.editing_morph = false;
+            } else {
+                // The partials stay as they are, free to edit, without the organ extras
+                patch.drawbars = None;
+                patch.click = 0.0;
+                patch.percussion = None;
+            }
+            changed = true;
+        }
+        if drawbars {
+            changed |= Slider::new(im_str!("key click"))
+                .range(0.0..=1.0)
+                .build(ui, &mut patch.click);
+
+            let mut percussion = patch.percussion.is_some();
+            if ui.checkbox(im_str!("percussion"), &mut percussion) {
+                patch.percussion = if percussion {
+                    Some(Percussion {
+                        third: false,
+                        soft: false,
+                        fast: true,
+                    })
+                } else {
+                    None
+                };
+                changed = true;
+            }
+            if let Some(percussion) = &mut patch.percussion {
+                ui.same_line(0.0);
+                changed |= ui.checkbox(im_str!("third"), &mut percussion.third);
+                ui.same_line(0.0);
+                changed |= ui.checkbox(im_str!("soft"), &mut percussion.soft);
+                ui.same_line(0.0);
+                changed |= ui.checkbox(im_str!("fast"), &mut percussion.fast);
+            }
+        }
+
+        // The drawbar view only edits A, so morphing is left out while the drawbars are on
+        if !drawbars {
+            ui.separator();
+
+            let mut morph = patch.morph.is_some();
+            if ui.checkbox(im_str!("morph"), &mut morph) {
+                if morph {
+                    patch.morph = Some(MorphSource::ModWheel);
+                    patch.match_morph_partials();
+                } else {
+                    patch.morph = None;
+                    self.editing_morph = false;
+                }
+                changed = true;
+            }
+            if let Some(source) = &mut patch.morph {
+                for &(label, option) in &[
+                    (im_str!("velocity"), MorphSource::Velocity),
+                    (im_str!("mod wheel"), MorphSource::ModWheel),
+                    (im_str!("aftertouch"), MorphSource::Aftertouch),
+                    (im_str!("lfo"), MorphSource::Lfo),
+                ] {
+                    ui.same_line(0.0);
+                    changed |= ui.radio_button(label, source, option);
+                }
+
+                if *source == MorphSource::Lfo {
+                    changed |= Slider::new(im_str!("lfo rate"))
+                        .range(0.01..=20.0)
+                        .display_format(im_str!("%.2f Hz"))
+                        .flags(SliderFlags::LOGARITHMIC)
+                        .build(ui, &mut patch.morph_rate);
+                }
+
+                ui.text("edit");
+                ui.same_line(0.0);
+                ui.radio_button(im_str!("A"), &mut self.editing_morph, false);
+                ui.same_line(0.0);
+                ui.radio_button(im_str!("B"), &mut self.editing_morph, true);
+                ui.same_line(0.0);
+                if ui.button(im_str!("Copy A to B"), [0.0, 0.0]) {
+                    patch.morph_partials = patch.partials.clone();
+                    changed = true;
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Swap A and B"), [0.0, 0.0]) {
+                    // The envelopes stay with A, they are the only ones used
+                    for (a, b) in patch.partials.iter_mut().zip(&mut patch.morph_partials) {
+                        std::mem::swap(&mut a.amplitude, &mut b.amplitude);
+                        std::mem::swap(&mut a.ratio, &mut b.ratio);
+                        std::mem::swap(&mut a.detune, &mut b.detune);
+                    }
+                    changed = true;
+                }
+            }
+        }
+
+        ui.separator();
+
+        Slider::new(im_str!("inharmonicity"))
+            .range(0.0..=0.01)
+            .display_format(im_str!("B = %.5f"))
+            .flags(SliderFlags::LOGARITHMIC)
+            .build(ui, &mut self.inharmonicity);
+        ui.same_line(0.0);
+        if ui.button(im_str!("Stretch"), [0.0, 0.0]) {
+            patch.stretch(self.inharmonicity);
+            changed = true;
+        }
+        ui.same_line(0.0);
+        if ui.button(im_str!("Harmonic"), [0.0, 0.0]) {
+            patch.harmonic();
+            changed = true;
+        }
+
+        Slider::new(im_str!("tilt"))
+            .range(0.0..=1.0)
+            .build(ui, &mut self.tilt);
+        ui.same_line(0.0);
+        if ui.button(im_str!("Apply to partials"), [0.0, 0.0]) {
+            patch.tilt_envelopes(self.tilt);
+            changed = true;
+        }
+        ui.same_line(0.0);
+        if ui.button(im_str!("Clear"), [0.0, 0.0]) {
+            for partial in &mut patch.partials {
+                partial.envelope = None;
+            }
+            changed = true;
+        }
+
+        ui.separator();
+
+        changed |= Slider::new(im_str!("volume"))
+            .range(0.0..=1.0)
+            .build(ui, &mut patch.volume);
+        changed |= Slider::new(im_str!("pan"))
+            .range(-1.0..=1.0)
+            .build(ui, &mut patch.pan);
+        changed |= Slider::new(im_str!("key tracking"))
+            .range(-1.0..=1.0)
+            .build(ui, &mut patch.key_tracking);
+        changed |= Slider::new(im_str!("stereo spread"))
+            .range(0.0..=1.0)
+            .build(ui, &mut patch.spread);
+
+        changed |= ui.checkbox(im_str!("Lanczos smoothing"), &mut patch.smoothing);
+
+        ui.separator();
+
+        let mut polyphony = patch.polyphony as i32;
+        if Slider::new(im_str!("polyphony"))
+            .range(1..=MAX_POLYPHONY as i32)
+            .build(ui, &mut polyphony)
+        {
+            patch.polyphony = polyphony as usize;
+            changed = true;
+        }
+
+        ui.text("steal");
+        for &(label, stealing) in &[
+            (im_str!("oldest"), Stealing::Oldest),
+            (im_str!("quietest"), Stealing::Quietest),
+            (im_str!("same note"), Stealing::SameNote),
+            (im_str!("released first"), Stealing::ReleasedFirst),
+        ] {
+            ui.same_line(0.0);
+            changed |= ui.radio_button(label, &mut patch.stealing, stealing);
+        }
+
+        ui.text("voices");
+        for &(label, mode) in &[
+            (im_str!("poly"), VoiceMode::Poly),
+            (im_str!("mono"), VoiceMode::Mono),
+            (im_str!("legato"), VoiceMode::Legato),
+        ] {
+            ui.same_line(0.0);
+            changed |= ui.radio_button(label, &mut patch.mode, mode);
+        }
+
+        if patch.mode != VoiceMode::Poly {
+            ui.text("priority");
+            for &(label, priority) in &[
+                (im_str!("last"), Priority::Last),
+                (im_str!("low"), Priority::Low),
+                (im_str!("high"), Priority::High),
+            ] {
+                ui.same_line(0.0);
+                changed |= ui.radio_button(label, &mut patch.priority, priority);
+            }
+
+            changed |= Slider::new(im_str!("glide"))
+                .range(0.0..=2.0)
+                .display_format(im_str!("%.3f s"))
+                .flags(SliderFlags::LOGARITHMIC)
+                .build(ui, &mut patch.glide);
+        }
+
+        changed
+    }
+}
@@ -0,0 +1,273 @@
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::thread;
+
+use imgui::*;
+
+use crate::analysis::{self, Analysis};
+use crate::patch::Patch;
+use crate::patch_file;
+use crate::presets::PRESETS;
+
+/// Lists the patches in a directory and saves, loads and renames them. Also picks factory
+/// presets and turns recordings into patches.
+pub struct PatchBrowser {
+    dir: ImString,
+    name: ImString,
+    names: Vec<String>,
+    selected: Option<usize>,
+    /// Name of an existing patch that Save is waiting for confirmation to replace.
+    overwrite: Option<String>,
+    /// Factory preset last picked from the combo box.
+    preset: usize,
+    /// Recording to analyze into a patch.
+    wav: ImString,
+    envelopes: bool,
+    /// Recording being analyzed on a worker thread, so a long one doesn't hold up the UI.
+    analyzing: Option<(PathBuf, mpsc::Receiver<Result<Analysis, anyhow::Error>>)>,
+    status: Option<String>,
+}
+
+impl PatchBrowser {
+    pub fn new() -> Self {
+        let mut dir = ImString::with_capacity(256);
+        dir.push_str(&patch_file::default_dir().to_string_lossy());
+
+        let mut browser = PatchBrowser {
+            dir,
+            name: ImString::with_capacity(64),
+            names: vec![],
+            selected: None,
+            overwrite: None,
+            preset: 0,
+            wav: ImString::with_capacity(256),
+            envelopes: true,
+            analyzing: None,
+            status: None,
+        };
+        browser.refresh();
+        browser
+    }
+
+    fn dir(&self) -> PathBuf {
+        PathBuf::from(self.dir.to_str())
+    }
+
+    fn refresh(&mut self) {
+        self.selected = None;
+        match patch_file::list(&self.dir()) {
+            Ok(names) => self.names = names,
+            Err(e) => {
+                self.names.clear();
+                self.status = Some(format!("failed to list {}: {}", self.dir.to_str(), e));
+            }
+        }
+    }
+
+    fn select(&mut self, name: &str) {
+        self.selected = self.names.iter().position(|n| n == name);
+    }
+
+    /// Draws the browser for the patch of the edited channel. Returns whether a patch, a factory
+    /// preset or an analyzed recording was loaded into it.
+    pub fn draw(&mut self, ui: &Ui, channel: usize, patch: &mut Patch) -> bool {
+        let mut loaded = false;
+
+        Window::new(im_str!("patches"))
+            .position([10.0, 400.0], Condition::FirstUseEver)
+            .always_auto_resize(true)
+            .build(ui, || {
+                if ComboBox::new(im_str!("factory preset")).build_simple(
+                    ui,
+                    &mut self.preset,
+                    &PRESETS,
+                    &|preset| Cow::Owned(ImString::new(preset.name)),
+                ) {
+                    *patch = PRESETS[self.preset].patch();
+                    loaded = true;
+                    self.status = Some(format!(
+                        "loaded {} on channel {}",
+                        PRESETS[self.preset].name,
+                        channel + 1
+                    ));
+                }
+
+                if ui.input_text(im_str!("directory"), &mut self.dir).build() {
+                    self.refresh();
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Refresh"), [0.0, 0.0]) {
+                    self.refresh();
+                }
+
+                let mut clicked = None;
+                ChildWindow::new("patch list")
+                    .size([300.0, 150.0])
+                    .border(true)
+                    .build(ui, || {
+                        for (i, name) in self.names.iter().enumerate() {
+                            if Selectable::new(&ImString::new(name))
+                                .selected(self.selected == Some(i))
+                                .allow_double_click(true)
+                                .build(ui)
+                            {
+                                clicked = Some((i, ui.is_mouse_double_clicked(MouseButton::Left)));
+                            }
+                        }
+                    });
+
+                ui.input_text(im_str!("name"), &mut self.name).build();
+
+                // Double click is a shortcut for Load
+                let mut load = ui.button(im_str!("Load"), [0.0, 0.0]);
+                if let Some((i, double)) = clicked {
+                    self.selected = Some(i);
+                    self.name.clear();
+                    self.name.push_str(&self.names[i]);
+                    load |= double;
+                }
+
+                let dir = self.dir();
+                let name = self.name.to_str().trim().to_string();
+
+                if load {
+                    match self.selected.map(|i| &self.names[i]) {
+                        Some(selected) => {
+                            match patch_file::path(&dir, selected)
+                                .and_then(|path| patch_file::load(&path))
+                            {
+                                Ok(read) => {
+                                    *patch = read;
+                                    loaded = true;
+                                    self.status = Some(format!(
+                                        "loaded {} on channel {}",
+                                        selected,
+                                        channel + 1
+                                    ));
+                                }
+                                Err(e) => {
+                                    self.status =
+                                        Some(format!("failed to load {}: {}", selected, e))
+                                }
+                            }
+                        }
+                        None => self.status = Some(String::from("select a patch to load")),
+                    }
+                }
+
+                ui.same_line(0.0);
+                let mut save = false;
+                if ui.button(im_str!("Save"), [0.0, 0.0]) {
+                    if name.is_empty() {
+                        self.status = Some(String::from("enter a name to save as"));
+                    } else {
+                        match patch_file::path(&dir, &name) {
+                            // Like Rename, only replaces another patch when asked to
+                            Ok(target) if target.exists() => self.overwrite = Some(name.clone()),
+                            Ok(_) => save = true,
+                            Err(e) => self.status = Some(format!("failed to save {}: {}", name, e)),
+                        }
+                    }
+                }
+
+                ui.same_line(0.0);
+                if ui.button(im_str!("Rename"), [0.0, 0.0]) {
+                    let selected = self.selected.map(|i| self.names[i].clone());
+                    self.status = Some(match selected {
+                        Some(_) if name.is_empty() => String::from("enter a new name"),
+                        Some(selected) => match patch_file::rename(&dir, &selected, &name) {
+                            Ok(()) => {
+                                self.refresh();
+                                self.select(&name);
+                                format!("renamed {} to {}", selected, name)
+                            }
+                            Err(e) => format!("failed to rename {}: {}", selected, e),
+                        },
+                        None => String::from("select a patch to rename"),
+                    });
+                }
+
+                // Editing the name takes back the question
+                if self.overwrite.as_deref().is_some_and(|n| n != name) {
+                    self.overwrite = None;
+                }
+                if let Some(overwrite) = &self.overwrite {
+                    ui.text(format!("{} already exists", overwrite));
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Overwrite"), [0.0, 0.0]) {
+                        save = true;
+                        self.overwrite = None;
+                    }
+                    ui.same_line(0.0);
+                    if ui.button(im_str!("Cancel"), [0.0, 0.0]) {
+                        self.overwrite = None;
+                    }
+                }
+
+                if save {
+                    let saved = patch_file::path(&dir, &name)
+                        .and_then(|path| patch_file::save(&path, patch));
+                    self.status = Some(match saved {
+                        Ok(()) => {
+                            self.refresh();
+                            self.select(&name);
+                            format!("saved channel {} as {}", channel + 1, name)
+                        }
+                        Err(e) => format!("failed to save {}: {}", name, e),
+                    });
+                }
+
+                ui.separator();
+                ui.input_text(im_str!("recording"), &mut self.wav).build();
+                ui.checkbox(im_str!("partial envelopes"), &mut self.envelopes);
+                ui.same_line(0.0);
+                if self.analyzing.is_some() {
+                    ui.text("analyzing...");
+                } else if ui.button(im_str!("Analyze"), [0.0, 0.0]) {
+                    let path = PathBuf::from(self.wav.to_str());
+                    let envelopes = self.envelopes;
+                    let (tx, rx) = mpsc::channel();
+                    let file = path.clone();
+                    thread::spawn(move || {
+                        // The browser may be gone by the time it's done, nothing to do then
+                        let _ = tx.send(analysis::analyze_file(&file, envelopes));
+                    });
+                    self.analyzing = Some((path, rx));
+                }
+
+                let finished = match &self.analyzing {
+                    Some((path, rx)) => match rx.try_recv() {
+                        Ok(result) => Some((path.clone(), result)),
+                        Err(mpsc::TryRecvError::Empty) => None,
+                        Err(mpsc::TryRecvError::Disconnected) => {
+                            Some((path.clone(), Err(anyhow::anyhow!("the analysis crashed"))))
+                        }
+                    },
+                    None => None,
+                };
+                if let Some((path, result)) = finished {
+                    self.analyzing = None;
+                    self.status = Some(match result {
+                        Ok(analysis) => {
+                            *patch = analysis.patch;
+                            loaded = true;
+                            format!(
+                                "analyzed {} into channel {}, fundamental {:.2} Hz",
+                                path.display(),
+                                channel + 1,
+                                analysis.fundamental
+                            )
+                        }
+                        Err(e) => format!("failed to analyze {}: {}", path.display(), e),
+                    });
+                }
+
+                if let Some(status) = &self.status {
+                    ui.text(status);
+                }
+            });
+
+        loaded
+    }
+}
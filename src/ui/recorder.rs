@@ -0,0 +1,120 @@
+use std::path::Path;
+
+use imgui::*;
+
+use crate::midi::{MidiEvent, MidiSource, Track};
+use crate::smf::Smf;
+
+pub struct Recorder {
+    recording: bool,
+    /// The last finished recording, one track per port.
+    take: Vec<Track>,
+    path: ImString,
+    bpm: f32,
+    ticks_per_quarter: i32,
+    status: Option<String>,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        let mut path = ImString::with_capacity(256);
+        path.push_str("recording.mid");
+
+        Recorder {
+            recording: false,
+            take: vec![],
+            path,
+            bpm: 120.0,
+            ticks_per_quarter: 480,
+            status: None,
+        }
+    }
+
+    /// Returns the take when a recording is stopped.
+    pub fn draw(&mut self, ui: &Ui, conns: &mut [MidiSource]) -> Option<Vec<Track>> {
+        let mut finished = None;
+
+        Window::new(im_str!("recorder"))
+            .position([10.0, 120.0], Condition::FirstUseEver)
+            .always_auto_resize(true)
+            .build(ui, || {
+                if self.recording {
+                    if ui.button(im_str!("Stop"), [0.0, 0.0]) {
+                        self.recording = false;
+
+                        // Start the take at the first recorded event
+                        let start = conns
+                            .iter()
+                            .filter_map(|c| c.input.first())
+                            .map(|e| e.time)
+                            .min()
+                            .unwrap_or(0);
+
+                        self.take = conns
+                            .iter()
+                            .map(|c| Track {
+                                name: c.name.clone(),
+                                events: c
+                                    .input
+                                    .iter()
+                                    .map(|e| MidiEvent {
+                                        input: e.input.clone(),
+                                        time: e.time - start,
+                                    })
+                                    .collect(),
+                            })
+                            .collect();
+                        finished = Some(self.take.clone());
+                    }
+                    ui.same_line(0.0);
+                    let events = conns.iter().map(|c| c.input.len()).sum::<usize>();
+                    ui.text_colored(
+                        [1.0, 0.3, 0.3, 1.0],
+                        format!("recording, {} events", events),
+                    );
+                } else if ui.button(im_str!("Record"), [0.0, 0.0]) {
+                    for conn in conns.iter_mut() {
+                        conn.input.clear();
+                    }
+                    self.recording = true;
+                    self.status = None;
+                }
+
+                Slider::new(im_str!("bpm"))
+                    .range(20.0..=300.0)
+                    .build(ui, &mut self.bpm);
+                if ui
+                    .input_int(im_str!("ticks per quarter"), &mut self.ticks_per_quarter)
+                    .build()
+                {
+                    self.ticks_per_quarter = self.ticks_per_quarter.clamp(24, 9600);
+                }
+
+                ui.input_text(im_str!("save as"), &mut self.path).build();
+                if ui.button(im_str!("Save"), [0.0, 0.0]) {
+                    self.status = Some(self.save());
+                }
+
+                if let Some(status) = &self.status {
+                    ui.text(status);
+                }
+            });
+
+        finished
+    }
+
+    fn save(&self) -> String {
+        if self.take.iter().all(|t| t.events.is_empty()) {
+            return String::from("nothing recorded yet");
+        }
+
+        let tempo = (60_000_000.0 / self.bpm) as u32;
+        let smf = Smf::from_timeline(&self.take, self.ticks_per_quarter as u16, tempo);
+
+        let path = Path::new(self.path.to_str());
+        match smf.write(path) {
+            Ok(()) => format!("saved to {}", path.display()),
+            Err(e) => format!("failed to save {}: {}", path.display(), e),
+        }
+    }
+}
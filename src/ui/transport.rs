@@ -0,0 +1,65 @@
+use imgui::*;
+
+use crate::player::{Transport, MAX_SPEED, MIN_SPEED};
+use crate::remote::{Command, Remote};
+
+pub fn draw_transport(ui: &Ui, remote: &mut Remote) {
+    let player = remote.status.player;
+
+    Window::new(im_str!("transport"))
+        .position([10.0, 300.0], Condition::FirstUseEver)
+        .always_auto_resize(true)
+        .build(ui, || {
+            let mut send = |transport| remote.send(Command::Transport(transport));
+
+            if player.playing {
+                if ui.button(im_str!("Pause"), [0.0, 0.0]) {
+                    send(Transport::Pause);
+                }
+            } else if ui.button(im_str!("Play"), [0.0, 0.0]) {
+                send(Transport::Play);
+            }
+            ui.same_line(0.0);
+            if ui.button(im_str!("Stop"), [0.0, 0.0]) {
+                send(Transport::Stop);
+            }
+
+            let length = player.length as f32 / 1_000_000.0;
+
+            let mut position = player.position as f32 / 1_000_000.0;
+            if Slider::new(im_str!("position"))
+                .range(0.0..=length)
+                .display_format(im_str!("%.2f s"))
+                .build(ui, &mut position)
+            {
+                send(Transport::Seek((position * 1_000_000.0) as u64));
+            }
+
+            let mut speed = player.speed;
+            if Slider::new(im_str!("speed"))
+                .range(MIN_SPEED..=MAX_SPEED)
+                .display_format(im_str!("%.2fx"))
+                .build(ui, &mut speed)
+            {
+                send(Transport::Speed(speed));
+            }
+
+            let mut looping = player.looping;
+            let mut region = [
+                player.loop_start as f32 / 1_000_000.0,
+                player.loop_end as f32 / 1_000_000.0,
+            ];
+            let mut changed = ui.checkbox(im_str!("loop"), &mut looping);
+            changed |= Slider::new(im_str!("loop region"))
+                .range(0.0..=length)
+                .display_format(im_str!("%.2f s"))
+                .build_array(ui, &mut region);
+            if changed {
+                send(Transport::Loop {
+                    enabled: looping,
+                    start: (region[0] * 1_000_000.0) as u64,
+                    end: (region[1].max(region[0]) * 1_000_000.0) as u64,
+                });
+            }
+        });
+}
@@ -0,0 +1,136 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use anyhow::bail;
+
+/// Writes interleaved samples as 16 bit PCM, clipping anything outside of [-1, 1].
+pub fn write(
+    path: &Path,
+    sample_rate: u32,
+    channels: u16,
+    samples: &[f32],
+) -> Result<(), anyhow::Error> {
+    let mut out = BufWriter::new(File::create(path)?);
+
+    let bytes_per_sample = 2;
+    let data_len = (samples.len() * bytes_per_sample) as u32;
+    let block_align = channels * bytes_per_sample as u16;
+
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&channels.to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&(bytes_per_sample as u16 * 8).to_le_bytes())?;
+
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    for &sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        out.write_all(&value.to_le_bytes())?;
+    }
+
+    out.flush()?;
+
+    Ok(())
+}
+
+fn u16_at(data: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([data[pos], data[pos + 1]])
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+/// Reads 8, 16, 24 or 32 bit PCM or 32 bit float, mixed down to mono. Returns the sample rate
+/// and the samples.
+pub fn read_mono(path: &Path) -> Result<(u32, Vec<f32>), anyhow::Error> {
+    let data = fs::read(path)?;
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        bail!("not a WAV file");
+    }
+
+    let mut format = None;
+    let mut samples = None;
+
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let len = u32_at(&data, pos + 4) as usize;
+        let body = &data[pos + 8..(pos + 8 + len).min(data.len())];
+
+        match id {
+            b"fmt " if body.len() >= 16 => {
+                // Format, channels, sample rate and bits per sample. WAVE_FORMAT_EXTENSIBLE
+                // keeps the actual format in the sub format GUID.
+                let mut tag = u16_at(body, 0);
+                if tag == 0xfffe && body.len() >= 26 {
+                    tag = u16_at(body, 24);
+                }
+                format = Some((tag, u16_at(body, 2), u32_at(body, 4), u16_at(body, 14)));
+            }
+            b"data" => samples = Some(body),
+            _ => {}
+        }
+
+        // Chunks are padded to an even length
+        pos += 8 + len + len % 2;
+    }
+
+    let (tag, channels, sample_rate, bits) = match format {
+        Some(format) => format,
+        None => bail!("no format chunk"),
+    };
+    let samples = match samples {
+        Some(samples) => samples,
+        None => bail!("no data chunk"),
+    };
+    if channels == 0 {
+        bail!("no channels");
+    }
+
+    let decode: fn(&[u8]) -> f32 = match (tag, bits) {
+        (1, 8) => |b| (b[0] as f32 - 128.0) / 128.0,
+        (1, 16) => |b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0,
+        (1, 24) => |b| i32::from_le_bytes([0, b[0], b[1], b[2]]) as f32 / 2147483648.0,
+        (1, 32) => |b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32 / 2147483648.0,
+        (3, 32) => |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+        _ => bail!("unsupported sample format {} with {} bits", tag, bits),
+    };
+
+    let width = bits as usize / 8;
+    let mono = samples
+        .chunks_exact(width * channels as usize)
+        .map(|frame| frame.chunks_exact(width).map(decode).sum::<f32>() / channels as f32)
+        .collect();
+
+    Ok((sample_rate, mono))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let path = std::env::temp_dir().join(format!("music-box-wav-{}.wav", std::process::id()));
+        write(&path, 44_100, 2, &[0.5, -0.5, 0.25, 0.25, -1.0, -1.0]).unwrap();
+
+        let (sample_rate, samples) = read_mono(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(sample_rate, 44_100);
+        assert_eq!(samples.len(), 3);
+        assert!(samples[0].abs() < 1e-4);
+        assert!((samples[1] - 0.25).abs() < 1e-4);
+        assert!((samples[2] + 1.0).abs() < 1e-4);
+    }
+}